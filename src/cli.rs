@@ -1,4 +1,5 @@
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 
 pub fn parse() -> CliArgs {
 	CliArgs::parse()
@@ -26,6 +27,28 @@ pub enum Command {
 		/// skip re-attaching PCI devices and such
 		#[arg(long, short)]
 		skip_attach: bool,
+
+		/// write all disk changes to temporary overlays, discarding them on exit
+		#[arg(long)]
+		ephemeral: bool,
+
+		/// keep the overlays of an ephemeral run
+		#[arg(long, requires = "ephemeral")]
+		keep_overlay: bool,
+	},
+
+	/// Run the VM once, booting from a Windows installation ISO.
+	Install {
+		#[command(flatten)]
+		config: Options,
+
+		/// installation ISO to boot from
+		#[arg(long)]
+		iso: PathBuf,
+
+		/// driver ISO to attach alongside, e.g. virtio-win.iso
+		#[arg(long)]
+		drivers: Option<PathBuf>,
 	},
 
 	/// Unload drivers and detach devices
@@ -39,6 +62,92 @@ pub enum Command {
 		#[command(flatten)]
 		config: Options,
 	},
+
+	/// Manage snapshots of the qcow2 disks in a profile, the VM must not be running
+	Snapshot {
+		#[command(flatten)]
+		config: Options,
+
+		#[command(subcommand)]
+		action: SnapshotAction,
+	},
+
+	/// Reset the UEFI variables of a profile to the firmware's template
+	ResetNvram {
+		#[command(flatten)]
+		config: Options,
+	},
+
+	/// Work with the host's raw SMBIOS tables
+	Smbios {
+		#[command(subcommand)]
+		action: SmbiosAction,
+	},
+
+	/// Dump the option ROM (VBIOS) of a PCI device
+	DumpRom {
+		/// PCI address of the device, e.g. 0000:01:00.0
+		address: String,
+
+		/// output file [default: <ADDRESS>.rom]
+		#[arg(long, short)]
+		output: Option<PathBuf>,
+
+		/// strip the header found in front of some NVIDIA ROMs
+		#[arg(long)]
+		strip_nvidia_header: bool,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SmbiosAction {
+	/// Dump the host's SMBIOS structures for use with smbios_file
+	Dump {
+		/// output file
+		#[arg(long, short, default_value = "smbios.bin")]
+		output: PathBuf,
+
+		/// leave out structures of this type, can be repeated
+		#[arg(long, short = 'x')]
+		exclude_type: Vec<u8>,
+
+		/// replace the system UUID
+		#[arg(long)]
+		uuid: Option<String>,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotAction {
+	/// Create a snapshot on every qcow2 disk
+	Create {
+		name: String,
+
+		/// keep the current state in a separate file <IMAGE>.<NAME> and continue in a new layer on top of it
+		#[arg(long)]
+		external: bool,
+	},
+
+	/// List the snapshots of every qcow2 disk
+	List,
+
+	/// Revert every qcow2 disk to a snapshot
+	Revert {
+		name: String,
+
+		/// revert to an external snapshot, deleting the external snapshots taken after it
+		#[arg(long)]
+		external: bool,
+	},
+
+	/// Delete a snapshot from every qcow2 disk
+	Delete {
+		name: String,
+
+		/// delete an external snapshot, merging it into the layer on top of it
+		#[arg(long)]
+		external: bool,
+	},
 }
 
 #[derive(Args, Debug)]
@@ -59,3 +168,12 @@ pub enum Profile {
 	/// start with GPU passthrough
 	Full,
 }
+
+impl Profile {
+	pub fn name(self) -> String {
+		self.to_possible_value()
+			.expect("profiles should not be skipped")
+			.get_name()
+			.to_owned()
+	}
+}
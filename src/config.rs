@@ -6,7 +6,19 @@ use crate::context::*;
 pub fn configure(config: &mut ContextBuilder, options: &Options) {
 	// These options always apply
 	config
-		.cpu("host,topoext,kvm=off,hv_frequencies,hv_time,hv_relaxed,hv_vapic,hv_spinlocks=0x1fff,hv_vendor_id=thisisnotavm")
+		.cpu_options(CpuOptions {
+			hide_kvm: true,
+			hyperv: vec![
+				HyperV::Frequencies,
+				HyperV::Time,
+				HyperV::Relaxed,
+				HyperV::Vapic,
+				HyperV::Spinlocks(0x1fff),
+			],
+			hv_vendor_id: Some(String::from("thisisnotavm")),
+			enable: vec![String::from("topoext")],
+			..Default::default()
+		})
 		.ovmf_bios("/usr/share/edk2/x64/OVMF.fd")
 		.smbios_auto()
 		.virtio_disk("/dev/disk/by-id/wwn-0x7666696f2d72756e")
@@ -1,13 +1,27 @@
+use super::numa::Placement;
 use super::util::{ArgWriter, EnvWriter, TmpFileWriter};
 use super::*;
+use super::{disk, dmi, mac};
 use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Uid};
 use std::fmt::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+// High enough to not collide with anything QEMU inherits otherwise
+const MACVTAP_FD: i32 = 100;
+const SOCKET_DIR: &str = "/run/vfio-run";
+const SMBIOS_DIR: &str = "/dev/shm/vfio-run/smbios";
 
 pub fn add_defaults(args: &mut ArgWriter) {
 	args.add_many(vec!["-nodefaults", "-enable-kvm"]);
 }
 
+// vCPU threads are only named "CPU <n>/KVM" with debug-threads, which pinning relies on to find them
+pub fn add_name(args: &mut ArgWriter, name: &str) {
+	args.add("-name")
+		.add(format!("{},debug-threads=on", name.replace(',', ",,")));
+}
+
 pub fn add_monitor(args: &mut ArgWriter) {
 	args.add_many(vec![
 		"-mon",
@@ -17,7 +31,14 @@ pub fn add_monitor(args: &mut ArgWriter) {
 	]);
 }
 
-pub fn add_system(args: &mut ArgWriter, cpu: Option<String>, smp: Option<String>, ram: String) {
+pub fn add_system(
+	args: &mut ArgWriter,
+	cpu: Option<String>,
+	smp: Option<String>,
+	ram: String,
+	shared_memory: bool,
+	placement: Option<&Placement>,
+) {
 	if let Some(cpu) = cpu {
 		args.add("-cpu").add(cpu);
 	}
@@ -26,12 +47,106 @@ pub fn add_system(args: &mut ArgWriter, cpu: Option<String>, smp: Option<String>
 		args.add("-smp").add(smp);
 	}
 
+	// vhost-user devices access guest memory directly, which needs to be shared for that
+	let (backend, share) = match shared_memory {
+		true => ("memory-backend-memfd", ",share=on"),
+		false => ("memory-backend-ram", ""),
+	};
+
+	match placement {
+		Some(placement) if !placement.guest_nodes.is_empty() => {
+			for (index, node) in placement.guest_nodes.iter().enumerate() {
+				let size = node.megabytes;
+				let host_node = node.host_node;
+
+				args.add("-object")
+					.add(format!(
+						"{backend},id=mem{index},size={size}M{share},host-nodes={host_node},policy=bind"
+					))
+					.add("-numa")
+					.add(format!(
+						"node,nodeid={index},{},memdev=mem{index}",
+						get_numa_cpus(&node.vcpus)
+					));
+			}
+		}
+		Some(placement) => {
+			let host_nodes = placement
+				.host_nodes
+				.iter()
+				.enumerate()
+				.map(|(index, node)| format!("host-nodes.{index}={node}"))
+				.collect::<Vec<_>>()
+				.join(",");
+
+			args.add("-object")
+				.add(format!("{backend},id=mem,size={ram}{share},{host_nodes},policy=bind"))
+				.add("-machine")
+				.add("memory-backend=mem");
+		}
+		None if shared_memory => {
+			args.add("-object")
+				.add(format!("{backend},id=mem,size={ram}{share}"))
+				.add("-machine")
+				.add("memory-backend=mem");
+		}
+		None => (),
+	}
+
 	args.add("-m").add(ram);
 }
 
-pub fn add_bios(args: &mut ArgWriter, bios: BiosType) {
+// a node may hold several ranges of vCPUs, each needs its own cpus= key
+fn get_numa_cpus(vcpus: &[usize]) -> String {
+	let mut ranges: Vec<(usize, usize)> = vec![];
+
+	for &vcpu in vcpus {
+		match ranges.last_mut() {
+			Some((_, end)) if *end + 1 == vcpu => *end = vcpu,
+			_ => ranges.push((vcpu, vcpu)),
+		}
+	}
+
+	ranges
+		.into_iter()
+		.map(|(start, end)| match start == end {
+			true => format!("cpus={start}"),
+			false => format!("cpus={start}-{end}"),
+		})
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+pub fn add_bios(args: &mut ArgWriter, bios: BiosType, nvram: Option<&Nvram>) {
 	match bios {
 		BiosType::Default => (),
+		BiosType::OvmfPflash { code, secure_boot, .. } => {
+			let nvram = nvram.expect("nvram should be set up for pflash firmware");
+
+			// distributions ship both raw and qcow2 firmware images
+			args.add("-drive").add(format!(
+				"if=pflash,unit=0,format={},readonly=on,file={}",
+				disk::detect_format(&code).as_str(),
+				escape(&code.to_string_lossy())
+			));
+			args.add("-drive").add(format!(
+				"if=pflash,unit=1,format={},file={}",
+				disk::detect_format(&nvram.template).as_str(),
+				escape(&nvram.path.to_string_lossy())
+			));
+
+			// Secure Boot firmware only allows SMM to write variables, which needs q35
+			if secure_boot {
+				args.add_many(vec![
+					"-machine",
+					"q35,smm=on",
+					"-global",
+					"driver=cfi.pflash01,property=secure,value=on",
+					"-global",
+					"ICH9-LPC.disable_s3=1",
+				]);
+			}
+		}
 		BiosType::Ovmf(path) => {
 			let firmware_directory = path.parent().expect("bios file should be in a directory");
 
@@ -43,16 +158,40 @@ pub fn add_bios(args: &mut ArgWriter, bios: BiosType) {
 	}
 }
 
-pub fn add_smbios(args: &mut ArgWriter, smbios: SmBiosMap) {
+pub fn add_smbios(
+	args: &mut ArgWriter,
+	tmp: &mut TmpFileWriter,
+	mut smbios: SmBiosMap,
+	structures: Vec<dmi::Structure>,
+) {
+	// QEMU refuses fields for types it also got binary structures for
+	smbios.retain(|smbios_type, _| !structures.iter().any(|s| s.kind == *smbios_type as u8));
+
 	for (smbios_type, fields) in smbios {
+		// QEMU builds the memory array itself and has no option for it
+		if smbios_type == SmBiosType::PhysicalMemoryArray {
+			log::error!("memory array fields require the host's structures through smbios_auto, ignoring them");
+			continue;
+		}
+
 		let mut buffer = format!("type={}", smbios_type as isize);
 
 		for (key, value) in fields {
-			write!(&mut buffer, ",{}={}", key, value.replace(',', ",,")).unwrap();
+			write!(&mut buffer, ",{}={}", key, value.replace(',', ",,")).expect("writing to a String is infallible");
 		}
 
 		args.add("-smbios").add(buffer);
 	}
+
+	// QEMU only looks at the type of the first structure in a file, one file each keeps it from adding its own
+	for (index, structure) in structures.into_iter().enumerate() {
+		let path = format!("{SMBIOS_DIR}/{index:03}-type{}.bin", structure.kind);
+
+		let mode = Mode::from_bits_truncate(0o644);
+		tmp.add_with_contents(&path, Uid::current(), Gid::current(), mode, structure.bytes);
+
+		args.add("-smbios").add(format!("file={path}"));
+	}
 }
 
 pub fn add_vga(args: &mut ArgWriter, vga: Vga) {
@@ -108,37 +247,372 @@ pub fn add_audio_frontend(args: &mut ArgWriter, audio_backend: AudioFrontend) {
 	}
 }
 
-pub fn add_networking(args: &mut ArgWriter, networking: Networking) {
-	match networking {
-		Networking::None => {
-			args.add_many(vec!["-nic", "none"]);
-		}
-		Networking::User => {
-			args.add_many(vec!["-nic", "model=e1000"]);
+pub fn add_networking(
+	args: &mut ArgWriter,
+	net_devices: &mut Vec<NetDevice>,
+	nics: Vec<Nic>,
+	user_net: &UserNetOptions,
+	profile: &str,
+) {
+	if nics.is_empty() {
+		args.add_many(vec!["-nic", "none"]);
+		return;
+	}
+
+	// each user NIC gets its own stack, forwarding the same ports twice would fail
+	let first_user = nics.iter().position(|n| n.networking.is_user_mode());
+
+	if first_user.is_none() && has_user_net_options(user_net) {
+		log::warn!("port forwards, smb share, guest subnet and dns only apply to user networking, ignoring them");
+	}
+
+	let no_options = UserNetOptions::default();
+	let user_options = |index| match first_user == Some(index) {
+		true => user_net,
+		false => &no_options,
+	};
+
+	for (index, nic) in nics.into_iter().enumerate() {
+		let mac = mac::resolve(&nic.mac, profile, index);
+		let id = format!("net{index}");
+
+		match nic.networking {
+			Networking::User => {
+				args.add("-nic").add(get_user_nic("e1000", user_options(index), mac));
+			}
+			Networking::VirtioUser => {
+				args.add("-nic")
+					.add(get_user_nic("virtio-net-pci", user_options(index), mac));
+			}
+			Networking::Bridge { bridge, model } => {
+				let name = format!("vfiotap{index}");
+				let mut device = format!("{},netdev={id}", model.as_str());
+				push_option(&mut device, "mac", mac);
+
+				args.add("-netdev")
+					.add(format!("tap,id={id},ifname={name},script=no,downscript=no"))
+					.add("-device")
+					.add(device);
+
+				net_devices.push(NetDevice::Tap { name, bridge });
+			}
+			Networking::Macvtap { parent, model } => {
+				// macvtap only passes frames addressed to its own MAC, so the NIC has to match it
+				let mac = mac.unwrap_or_else(|| format!("52:54:00:12:34:{:02x}", 0x56 + index));
+				let fd = MACVTAP_FD + index as i32;
+
+				args.add("-netdev")
+					.add(format!("tap,id={id},fd={fd}"))
+					.add("-device")
+					.add(format!("{},netdev={id},mac={mac}", model.as_str()));
+
+				net_devices.push(NetDevice::Macvtap {
+					name: format!("vfiomacvtap{index}"),
+					parent,
+					mac,
+					fd,
+				});
+			}
+			Networking::Passt { model } => {
+				let options = user_options(index);
+				let socket = PathBuf::from(format!("{SOCKET_DIR}/passt{index}.socket"));
+
+				if options.smb.is_some() || options.net.is_some() || options.dns.is_some() {
+					log::warn!("smb share, guest subnet and dns are not supported with passt, ignoring them");
+				}
+
+				let mut device = format!("{},netdev={id}", model.as_str());
+				push_option(&mut device, "mac", mac);
+
+				args.add("-netdev")
+					.add(format!(
+						"stream,id={id},server=off,addr.type=unix,addr.path={}",
+						escape(&socket.to_string_lossy())
+					))
+					.add("-device")
+					.add(device);
+
+				net_devices.push(NetDevice::Passt {
+					socket,
+					forwards: options.forwards.clone(),
+				});
+			}
 		}
-		Networking::VirtioUser => {
-			args.add_many(vec!["-nic", "model=virtio-net-pci"]);
+	}
+}
+
+fn get_user_nic(model: &str, options: &UserNetOptions, mac: Option<String>) -> String {
+	let mut nic = format!("user,model={model}");
+
+	push_option(&mut nic, "mac", mac);
+	push_option(&mut nic, "net", options.net.clone());
+	push_option(&mut nic, "dns", options.dns.clone());
+	push_option(
+		&mut nic,
+		"smb",
+		options.smb.as_ref().map(|p| p.to_string_lossy().into_owned()),
+	);
+
+	for forward in &options.forwards {
+		let protocol = forward.protocol.as_str();
+		nic.push_str(&format!(
+			",hostfwd={protocol}::{}-:{}",
+			forward.host_port, forward.guest_port
+		));
+	}
+
+	nic
+}
+
+fn has_user_net_options(options: &UserNetOptions) -> bool {
+	!options.forwards.is_empty() || options.smb.is_some() || options.net.is_some() || options.dns.is_some()
+}
+
+pub fn add_shared_folders(args: &mut ArgWriter, daemons: &mut Vec<Daemon>, folders: Vec<SharedFolder>) {
+	for (index, folder) in folders.into_iter().enumerate() {
+		let id = format!("fs{index}");
+		let socket = PathBuf::from(format!("{SOCKET_DIR}/virtiofs{index}.socket"));
+
+		args.add("-chardev")
+			.add(format!("socket,id={id},path={}", escape(&socket.to_string_lossy())))
+			.add("-device")
+			.add(format!("vhost-user-fs-pci,chardev={id},tag={}", escape(&folder.tag)));
+
+		daemons.push(Daemon::Virtiofsd {
+			socket,
+			path: folder.path,
+		});
+	}
+}
+
+pub fn add_tpm(args: &mut ArgWriter, daemons: &mut Vec<Daemon>, tpm: Tpm, state: PathBuf) {
+	let device = match tpm {
+		Tpm::No => return,
+		Tpm::Tis => "tpm-tis",
+		Tpm::Crb => "tpm-crb",
+	};
+
+	let socket = PathBuf::from(format!("{SOCKET_DIR}/swtpm/swtpm.socket"));
+
+	args.add("-chardev")
+		.add(format!(
+			"socket,id=tpm0-ctrl,path={}",
+			escape(&socket.to_string_lossy())
+		))
+		.add("-tpmdev")
+		.add("emulator,id=tpm0,chardev=tpm0-ctrl")
+		.add("-device")
+		.add(format!("{device},tpmdev=tpm0"));
+
+	daemons.push(Daemon::Swtpm { socket, state });
+}
+
+pub fn add_pci(args: &mut ArgWriter, devices: &[PciDevice]) {
+	for device in devices {
+		let mut config = format!("vfio-pci,host={}", device.address);
+
+		if let Some(romfile) = &device.romfile {
+			config.push_str(&format!(",romfile={}", escape(&romfile.to_string_lossy())));
 		}
+
+		args.add("-device").add(config);
 	}
 }
 
-pub fn add_pci(args: &mut ArgWriter, devices: &[String]) {
-	for address in devices {
-		args.add("-device").add(format!("vfio-pci,host={address}"));
+pub fn add_disks(args: &mut ArgWriter, disks: &[Disk], boot_order: &[PathBuf]) {
+	for (index, disk) in disks.iter().enumerate() {
+		let node = format!("disk{index}");
+
+		if disk.options.iothread {
+			args.add("-object").add(format!("iothread,id=io{index}"));
+		}
+
+		add_blockdev(args, disk, &node);
+		add_disk_device(args, &disk.options, &node, index, boot_index(boot_order, &disk.path));
 	}
 }
 
-pub fn add_disks(args: &mut ArgWriter, disks: Vec<Disk>) {
-	for disk in &disks {
-		match disk {
-			Disk::Raw(device) => args.add("-drive").add(raw_disk(device, "media=disk")),
-			Disk::Virtio(device) => args.add("-drive").add(raw_disk(device, "if=virtio")),
+// Two nodes per disk: the protocol node accessing the file or device, and the format node on top
+fn add_blockdev(args: &mut ArgWriter, disk: &Disk, node: &str) {
+	// the overlay takes the disk's place, QEMU follows its backing file
+	let (file, format) = match &disk.overlay {
+		Some(overlay) => (overlay, DiskFormat::Qcow2),
+		None => (&disk.path, disk.format),
+	};
+
+	let driver = match disk::is_block_device(file) {
+		true => "host_device",
+		false => "file",
+	};
+
+	let options = &disk.options;
+	let file = escape(&file.to_string_lossy());
+	let mut protocol = format!("driver={driver},filename={file},node-name={node}-file");
+	let mut format = format!("driver={},file={node}-file,node-name={node}", format.as_str());
+
+	if let Some(cache) = options.cache {
+		let (direct, no_flush) = match cache {
+			DiskCache::None | DiskCache::DirectSync => ("on", "off"),
+			DiskCache::Writeback | DiskCache::Writethrough => ("off", "off"),
+			DiskCache::Unsafe => ("off", "on"),
 		};
+
+		let cache = format!(",cache.direct={direct},cache.no-flush={no_flush}");
+		protocol.push_str(&cache);
+		format.push_str(&cache);
+	}
+
+	if let Some(aio) = options.aio {
+		protocol.push_str(match aio {
+			DiskAio::Threads => ",aio=threads",
+			DiskAio::Native => ",aio=native",
+			DiskAio::IoUring => ",aio=io_uring",
+		});
 	}
 
-	fn raw_disk(device: &Path, options: &str) -> String {
-		let dev = device.to_string_lossy();
-		format!("file={dev},format=raw,{options}")
+	if options.discard {
+		protocol.push_str(",discard=unmap");
+		format.push_str(",discard=unmap");
+	}
+
+	if options.detect_zeroes {
+		format.push_str(match options.discard {
+			true => ",detect-zeroes=unmap",
+			false => ",detect-zeroes=on",
+		});
+	}
+
+	args.add("-blockdev").add(protocol).add("-blockdev").add(format);
+}
+
+fn add_disk_device(args: &mut ArgWriter, options: &DiskOptions, node: &str, index: usize, boot_index: String) {
+	let iothread = match options.iothread {
+		true => format!(",iothread=io{index}"),
+		false => String::new(),
+	};
+
+	let mut device = match options.bus {
+		DiskBus::Ide => format!("ide-hd,drive={node}"),
+		DiskBus::Ahci => {
+			args.add("-device").add(format!("ahci,id=ahci{index}"));
+			format!("ide-hd,drive={node},bus=ahci{index}.0")
+		}
+		DiskBus::VirtioBlk => format!("virtio-blk-pci,drive={node}{iothread}"),
+		DiskBus::VirtioScsi => {
+			// iothreads are set per controller, so every disk gets its own
+			args.add("-device")
+				.add(format!("virtio-scsi-pci,id=scsi{index}{iothread}"));
+			format!("scsi-hd,drive={node},bus=scsi{index}.0")
+		}
+		DiskBus::Nvme => {
+			let serial = options.identity.serial.as_deref().unwrap_or(node);
+			format!("nvme,drive={node},serial={}", escape(serial))
+		}
+	};
+
+	if options.iothread && !matches!(options.bus, DiskBus::VirtioBlk | DiskBus::VirtioScsi) {
+		log::warn!("iothreads are not supported on {:?}, ignoring for {node}", options.bus);
+	}
+
+	if let Some(cache) = options.cache {
+		device.push_str(match cache {
+			DiskCache::Writethrough | DiskCache::DirectSync => ",write-cache=off",
+			DiskCache::None | DiskCache::Writeback | DiskCache::Unsafe => ",write-cache=on",
+		});
+	}
+
+	if let Some(rotation_rate) = options.rotation_rate {
+		match options.bus {
+			DiskBus::Ide | DiskBus::Ahci | DiskBus::VirtioScsi => {
+				device.push_str(&format!(",rotation_rate={rotation_rate}"));
+			}
+			DiskBus::VirtioBlk | DiskBus::Nvme => {
+				log::warn!(
+					"rotation rate is not supported on {:?}, ignoring for {node}",
+					options.bus
+				);
+			}
+		}
+	}
+
+	if let Some(size) = options.physical_block_size {
+		device.push_str(&format!(",physical_block_size={size}"));
+	}
+
+	add_disk_identity(&mut device, &options.identity, options.bus, node);
+	device.push_str(&boot_index);
+	args.add("-device").add(device);
+}
+
+fn add_disk_identity(device: &mut String, identity: &DiskIdentity, bus: DiskBus, node: &str) {
+	// NVMe requires a serial number, it's already set along with the device
+	if !matches!(bus, DiskBus::Nvme) {
+		push_option(device, "serial", identity.serial.clone());
+	}
+
+	let supported = match bus {
+		DiskBus::Ide | DiskBus::Ahci => {
+			push_option(device, "wwn", identity.wwn.map(|wwn| format!("0x{wwn:016x}")));
+			push_option(device, "model", ide_model(identity));
+			true
+		}
+		DiskBus::VirtioScsi => {
+			push_option(device, "wwn", identity.wwn.map(|wwn| format!("0x{wwn:016x}")));
+			push_option(device, "vendor", identity.vendor.clone());
+			push_option(device, "product", identity.product.clone());
+			true
+		}
+		DiskBus::VirtioBlk | DiskBus::Nvme => false,
+	};
+
+	let unsupported = identity.wwn.is_some() || identity.vendor.is_some() || identity.product.is_some();
+
+	if !supported && unsupported {
+		log::warn!("{bus:?} only supports a serial number, ignoring the rest of the identity for {node}");
+	}
+
+	// ATA disks report "ATA" as vendor in sysfs, that's not part of the model
+	fn ide_model(identity: &DiskIdentity) -> Option<String> {
+		match (&identity.vendor, &identity.product) {
+			(Some(vendor), Some(product)) if vendor != "ATA" => Some(format!("{vendor} {product}")),
+			(_, Some(product)) => Some(product.clone()),
+			(Some(vendor), None) if vendor != "ATA" => Some(vendor.clone()),
+			_ => None,
+		}
+	}
+}
+
+fn push_option(buffer: &mut String, key: &str, value: Option<String>) {
+	if let Some(value) = value {
+		buffer.push_str(&format!(",{key}={}", escape(&value)));
+	}
+}
+
+pub fn add_cdroms(args: &mut ArgWriter, cdroms: &[PathBuf], boot_order: &[PathBuf]) {
+	for (index, cdrom) in cdroms.iter().enumerate() {
+		let id = format!("cd{index}");
+		let file = escape(&cdrom.to_string_lossy());
+
+		// unlike -blockdev, -drive names the block backend, which the monitor's change and eject commands look up
+		args.add_many(vec![
+			"-drive",
+			&format!("if=none,id={id},media=cdrom,format=raw,readonly=on,file={file}"),
+			"-device",
+			&format!("ide-cd,drive={id}{}", boot_index(boot_order, cdrom)),
+		]);
+	}
+}
+
+pub fn add_boot(args: &mut ArgWriter, boot_menu: bool) {
+	if boot_menu {
+		args.add_many(vec!["-boot", "menu=on"]);
+	}
+}
+
+fn boot_index(boot_order: &[PathBuf], path: &Path) -> String {
+	match boot_order.iter().position(|p| p == path) {
+		Some(index) => format!(",bootindex={index}"),
+		None => String::new(),
 	}
 }
 
@@ -171,9 +645,10 @@ pub fn add_looking_glass(args: &mut ArgWriter, tmp: &mut TmpFileWriter, config:
 	let mode = Mode::from_bits_truncate(0o644);
 	tmp.add("/dev/shm/looking-glass", uid, gid, mode);
 
+	// the root bus is pci.0 on i440fx and pcie.0 on q35, QEMU picks it without a bus given
 	args.add_many(vec![
 		"-device",
-		"ivshmem-plain,memdev=ivshmem,bus=pci.0",
+		"ivshmem-plain,memdev=ivshmem",
 		"-object",
 		"memory-backend-file,id=ivshmem,share=on,mem-path=/dev/shm/looking-glass,size=32M",
 	]);
@@ -210,3 +685,8 @@ pub fn add_spice_agent(args: &mut ArgWriter, config: SpiceAgent) {
 		}
 	};
 }
+
+/// Escapes commas in option values, QEMU uses them as separators.
+fn escape(value: &str) -> String {
+	value.replace(',', ",,")
+}
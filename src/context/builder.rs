@@ -1,7 +1,19 @@
 use super::util::{ArgWriter, EnvWriter, TmpFileWriter};
-use super::{smbios::SmBiosMapExt, *};
+use super::{cpu, disk, dmi, firmware, identity, numa, rng, smbios::SmBiosMapExt, topology, unattend, *};
 use nix::unistd::{Gid, Uid};
-use std::path::PathBuf;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+const UNATTEND_ISO: &str = "/dev/shm/vfio-run/autounattend.iso";
+const STATE_DIR: &str = "/var/lib/vfio-run";
+
+// Where the CPU topology is derived from, instead of setting `-smp` and the affinity by hand
+#[derive(Clone, Copy, Debug)]
+enum AutoTopology {
+	Cores(usize),
+	Affinity,
+}
 
 #[derive(Debug)]
 pub struct ContextBuilder {
@@ -10,21 +22,40 @@ pub struct ContextBuilder {
 	ram: String,
 	bios_type: BiosType,
 	smbios: SmBiosMap,
+	smbios_file: Option<PathBuf>,
+	smbios_structures: bool,
+	smbios_randomize: bool,
+	tpm: Tpm,
 	vga: Vga,
 	window: Window,
 	audio_backend: AudioBackend,
 	audio_frontend: AudioFrontend,
-	networking: Networking,
+	profile: String,
+	nics: Vec<Nic>,
+	user_net: UserNetOptions,
 	looking_glass: LookingGlass,
 	spice: Spice,
 	spice_agent: SpiceAgent,
+	shared_folders: Vec<SharedFolder>,
 	disks: Vec<Disk>,
-	pci: Vec<String>,
+	cdroms: Vec<PathBuf>,
+	boot_menu: bool,
+	boot_order: Vec<PathBuf>,
+	installing: bool,
+	unattend: Option<Unattend>,
+	ephemeral: Ephemeral,
+	overlay_dir: PathBuf,
+	pci: Vec<PciDevice>,
 	pat_dealloc: Vec<String>,
 	unload_drivers: Option<Vec<String>>,
+	release_framebuffer: bool,
+	display_manager: Option<String>,
+	terminate_gpu_holders: bool,
 	usb: Vec<UsbDevice>,
 	cpu_affinity: Option<String>,
 	cpu_governor: Option<String>,
+	auto_topology: Option<AutoTopology>,
+	numa: Option<Numa>,
 }
 
 impl Default for ContextBuilder {
@@ -35,21 +66,40 @@ impl Default for ContextBuilder {
 			ram: String::from("4G"),
 			bios_type: BiosType::Default,
 			smbios: SmBiosMap::default(),
+			smbios_file: None,
+			smbios_structures: false,
+			smbios_randomize: false,
+			tpm: Tpm::No,
 			vga: Vga::None,
 			window: Window::None,
 			audio_backend: AudioBackend::None,
 			audio_frontend: AudioFrontend::None,
-			networking: Networking::None,
+			profile: String::from("default"),
+			nics: Vec::default(),
+			user_net: UserNetOptions::default(),
 			looking_glass: LookingGlass::No,
 			spice: Spice::No,
 			spice_agent: SpiceAgent::No,
+			shared_folders: Vec::default(),
 			disks: Vec::default(),
+			cdroms: Vec::default(),
+			boot_menu: false,
+			boot_order: Vec::default(),
+			installing: false,
+			unattend: None,
+			ephemeral: Ephemeral::No,
+			overlay_dir: PathBuf::from("/dev/shm/vfio-run"),
 			pci: Vec::default(),
 			pat_dealloc: Vec::default(),
 			unload_drivers: None,
+			release_framebuffer: false,
+			display_manager: None,
+			terminate_gpu_holders: false,
 			usb: Vec::default(),
 			cpu_affinity: None,
 			cpu_governor: None,
+			auto_topology: None,
+			numa: None,
 		}
 	}
 }
@@ -57,18 +107,54 @@ impl Default for ContextBuilder {
 // Not all configs use all methods
 #[allow(dead_code)]
 impl ContextBuilder {
+	/// Name of the profile being configured, used to derive stable per-profile values. Set automatically.
+	pub fn profile(&mut self, name: impl Into<String>) -> &mut Self {
+		self.profile = name.into();
+		self
+	}
+
 	/// CPU options for QEMU. See `qemu-system-x86_64 -cpu help`.
 	pub fn cpu(&mut self, options: impl Into<String>) -> &mut Self {
 		self.cpu = Some(options.into());
 		self
 	}
 
+	/// Typed alternative to [`ContextBuilder::cpu`]. Conflicting options are dropped and reported, see [`CpuOptions::windows_gaming`] for a start.
+	pub fn cpu_options(&mut self, mut options: CpuOptions) -> &mut Self {
+		for conflict in cpu::sanitize(&mut options) {
+			log::error!("dropping invalid cpu option: {conflict}");
+		}
+
+		self.cpu = Some(cpu::render(&options));
+		self
+	}
+
 	/// Implements CPU pinning. See `--cpu-list` of [taskset(1)](https://man7.org/linux/man-pages/man1/taskset.1.html).
 	pub fn cpu_affinity(&mut self, affinity: impl Into<String>) -> &mut Self {
 		self.cpu_affinity = Some(affinity.into());
 		self
 	}
 
+	/// Picks `cores` host cores with their SMT siblings, preferring ones sharing an L3 cache, i.e. a single CCD on Ryzen.
+	/// Derives `-smp`, the affinity and a pin for every vCPU from them, replacing [`ContextBuilder::smp`] and [`ContextBuilder::cpu_affinity`].
+	pub fn smp_auto(&mut self, cores: usize) -> &mut Self {
+		self.auto_topology = Some(AutoTopology::Cores(cores));
+		self
+	}
+
+	/// Derives `-smp` and a pin for every vCPU from the host CPUs in [`ContextBuilder::cpu_affinity`], replacing [`ContextBuilder::smp`].
+	pub fn smp_from_affinity(&mut self) -> &mut Self {
+		self.auto_topology = Some(AutoTopology::Affinity);
+		self
+	}
+
+	/// Keeps guest RAM on the host's NUMA nodes closest to the pinned CPUs and the GPU, read from `/sys/devices/system/node`.
+	/// Does nothing on hosts with a single node.
+	pub fn numa(&mut self, numa: Numa) -> &mut Self {
+		self.numa = Some(numa);
+		self
+	}
+
 	/// Sets the CPU frequency governor. See [cpupower-frequency-set(1)](https://linux.die.net/man/1/cpupower-frequency-set).  
 	/// The governor is NOT reset on exit.
 	pub fn cpu_governor(&mut self, governor: impl Into<String>) -> &mut Self {
@@ -95,14 +181,57 @@ impl ContextBuilder {
 		self
 	}
 
+	/// Boot in UEFI mode with split firmware, e.g. `OVMF_CODE.fd` and `OVMF_VARS.fd`.  
+	/// The variable store template is copied to `/var/lib/vfio-run/<profile>` on first run, so boot entries and keys persist.
+	pub fn ovmf_pflash(&mut self, code: impl Into<PathBuf>, vars_template: impl Into<PathBuf>) -> &mut Self {
+		self.bios_type = BiosType::OvmfPflash {
+			code: code.into(),
+			vars_template: vars_template.into(),
+			secure_boot: false,
+		};
+
+		self
+	}
+
+	/// Like [`ContextBuilder::ovmf_pflash`], for Secure Boot capable firmware, e.g. `OVMF_CODE.secboot.fd`.  
+	/// Switches to the q35 machine type with SMM. Use a variable store with enrolled keys to have Secure Boot enabled.
+	pub fn ovmf_pflash_secure_boot(
+		&mut self,
+		code: impl Into<PathBuf>,
+		vars_template: impl Into<PathBuf>,
+	) -> &mut Self {
+		self.bios_type = BiosType::OvmfPflash {
+			code: code.into(),
+			vars_template: vars_template.into(),
+			secure_boot: true,
+		};
+
+		self
+	}
+
+	/// Boot in UEFI mode, picking the firmware from QEMU's firmware descriptors in `/usr/share/qemu/firmware`.  
+	/// Split firmware is used like [`ContextBuilder::ovmf_pflash`]. Check the log to see which firmware was chosen.
+	pub fn ovmf_auto(&mut self, features: OvmfFeatures) -> &mut Self {
+		match firmware::find_ovmf(features) {
+			Some(bios) => self.bios_type = bios,
+			None => log::error!("no firmware matching {features:?} found, falling back to BIOS"),
+		}
+
+		self
+	}
+
 	/// Fills in SMBIOS fields from the host, falling back to defaults when unavailable.  
+	/// Processors, memory devices, port connectors and slots are copied from the host as well, scaled to the guest's RAM and `-smp`.
 	/// This *may* fool some Anticheat's VM detection.
 	pub fn smbios_auto(&mut self) -> &mut Self {
 		smbios::populate_auto(&mut self.smbios);
+		self.smbios_structures = true;
 		self
 	}
 
-	/// Sets the specified SMBIOS fields. Use after [`ContextBuilder::smbios_auto`] to overwrite select fields.
+	/// Sets the specified SMBIOS fields. Use after [`ContextBuilder::smbios_auto`] to overwrite select fields.  
+	/// Processor and memory device fields are ignored when the host's structures are used,
+	/// port connector, system slot and memory array fields are set on every one of the host's structures of that type.
 	pub fn smbios(
 		&mut self,
 		smbios_type: SmBiosType,
@@ -112,15 +241,154 @@ impl ContextBuilder {
 		self
 	}
 
+	/// Passes the SMBIOS structures in `path` to the guest as-is, see `vfio-run smbios dump`.  
+	/// Takes precedence over fields of the same types set through [`ContextBuilder::smbios_auto`] and [`ContextBuilder::smbios`].
+	pub fn smbios_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.smbios_file = Some(path.into());
+		self
+	}
+
+	/// Replaces serial numbers, asset tags and the system UUID with made up values in the format of the originals.  
+	/// They are derived from a seed in `/var/lib/vfio-run/<profile>/smbios-seed`, so they stay the same across runs.
+	/// Applies to values set through [`ContextBuilder::smbios`] and [`ContextBuilder::smbios_file`] as well.
+	pub fn smbios_randomize(&mut self) -> &mut Self {
+		self.smbios_randomize = true;
+		self
+	}
+
+	/// Adds a TPM 2.0 emulated by swtpm, as required by Windows 11.  
+	/// Its state persists in `/var/lib/vfio-run/<profile>/tpm`, deleting it is like swapping the TPM chip.
+	pub fn tpm(&mut self, tpm: Tpm) -> &mut Self {
+		self.tpm = tpm;
+		self
+	}
+
 	/// Adds a physical disk. Compatible with Windows out-of-the box, but has slow performance.
 	pub fn raw_disk(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-		self.disks.push(Disk::Raw(path.into()));
-		self
+		self.add_disk(path.into(), DiskFormat::Raw, DiskBus::Ide.into())
 	}
 
 	/// Adds a physical disk with VirtIO. Faster, but requires driver installation on guest.
 	pub fn virtio_disk(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-		self.disks.push(Disk::Virtio(path.into()));
+		self.add_disk(path.into(), DiskFormat::Raw, DiskBus::VirtioBlk.into())
+	}
+
+	/// Adds a disk image file, detecting its format (qcow2, vmdk, raw) from the header.
+	/// Images referring to other files are refused, except for external snapshots.
+	/// Compatible with Windows out-of-the box, but has slow performance.
+	pub fn image_disk(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.add_image(path.into(), DiskBus::Ide.into())
+	}
+
+	/// Adds a disk image file with VirtIO, detecting its format (qcow2, vmdk, raw) from the header.
+	/// Faster, but requires driver installation on guest.
+	pub fn virtio_image_disk(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.add_image(path.into(), DiskBus::VirtioBlk.into())
+	}
+
+	/// Adds a physical disk or image file with full control over how it's attached, see [`DiskOptions`].
+	/// Physical disks are raw, the format of image files is detected unless set. Use [`DiskOptions::ssd`] for physical SSDs.
+	pub fn disk(&mut self, path: impl Into<PathBuf>, options: DiskOptions) -> &mut Self {
+		self.add_image(path.into(), options)
+	}
+
+	/// Fills in serial number, WWN, vendor and model of the disk at `path` from the physical disk, instead of "QEMU HARDDISK".  
+	/// Complements [`ContextBuilder::smbios_auto`]. Values set in [`DiskIdentity`] take precedence.
+	pub fn disk_identity_auto(&mut self, path: impl AsRef<Path>) -> &mut Self {
+		let host = disk::read_identity(path.as_ref());
+		let Some(disk) = self.disk_entry(path.as_ref()) else {
+			return self;
+		};
+		let identity = &mut disk.options.identity;
+
+		identity.serial = identity.serial.take().or(host.serial);
+		identity.wwn = identity.wwn.or(host.wwn);
+		identity.vendor = identity.vendor.take().or(host.vendor);
+		identity.product = identity.product.take().or(host.product);
+
+		self
+	}
+
+	/// Redirects all writes to the disk at `path` into a qcow2 overlay at `overlay`, leaving the disk untouched.  
+	/// The overlay is recreated on every run, making for throwaway sessions.
+	pub fn disk_overlay(&mut self, path: impl AsRef<Path>, overlay: impl Into<PathBuf>) -> &mut Self {
+		if let Some(disk) = self.disk_entry(path.as_ref()) {
+			disk.overlay = Some(overlay.into());
+		}
+
+		self
+	}
+
+	/// Shares the host directory `path` with the guest through virtiofs, a virtiofsd instance is started for each folder.  
+	/// Windows guests need WinFsp and the VirtIO-FS service, which mounts the folder by `tag`.
+	pub fn shared_folder(&mut self, path: impl Into<PathBuf>, tag: impl Into<String>) -> &mut Self {
+		self.shared_folders.push(SharedFolder {
+			path: path.into(),
+			tag: tag.into(),
+		});
+
+		self
+	}
+
+	/// Adds a CD-ROM drive with the specified ISO image inserted.  
+	/// Media can be changed at runtime in the monitor with `change cd<N> <path>` and ejected with `eject cd<N>`,
+	/// N counting up from 0 in the order drives were added.
+	pub fn cdrom(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.cdroms.push(path.into());
+		self
+	}
+
+	/// Shows the firmware's boot menu on startup.
+	pub fn boot_menu(&mut self) -> &mut Self {
+		self.boot_menu = true;
+		self
+	}
+
+	/// Boots from the specified disks and CD-ROMs, identified by their paths, in that order.
+	/// Devices not listed come after them.
+	pub fn boot_order(&mut self, paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> &mut Self {
+		self.boot_order = paths.into_iter().map(Into::into).collect();
+		self
+	}
+
+	/// Boots from the installation ISO `iso` for this run, with the driver ISO `drivers` attached alongside.
+	/// Also attaches the [unattend](`ContextBuilder::unattend`) answer file, if configured.
+	pub fn install(&mut self, iso: impl Into<PathBuf>, drivers: Option<impl Into<PathBuf>>) -> &mut Self {
+		let iso = iso.into();
+
+		self.boot_order.insert(0, iso.clone());
+		self.cdrom(iso);
+
+		if let Some(drivers) = drivers {
+			self.cdrom(drivers);
+		}
+
+		self.installing = true;
+		self
+	}
+
+	/// Generates an `autounattend.xml` from `answers` and attaches it during [installs](`ContextBuilder::install`),
+	/// so Windows installs without any clicks. Loads the VirtIO drivers from the driver ISO.  
+	/// **Wipes the target disk.**
+	pub fn unattend(&mut self, answers: Unattend) -> &mut Self {
+		self.unattend = Some(answers);
+		self
+	}
+
+	/// Wraps every disk in a temporary qcow2 overlay in the [overlay directory](`ContextBuilder::overlay_dir`),
+	/// throwing away all changes made by the guest. The overlays are deleted after QEMU exits, unless `keep_overlays` is set.
+	pub fn ephemeral(&mut self, keep_overlays: bool) -> &mut Self {
+		self.ephemeral = match keep_overlays {
+			true => Ephemeral::Keep,
+			false => Ephemeral::Discard,
+		};
+
+		self
+	}
+
+	/// Directory for the overlays created by [`ContextBuilder::ephemeral`]. Defaults to `/dev/shm/vfio-run`, which is backed by RAM.
+	pub fn overlay_dir(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.overlay_dir = path.into();
 		self
 	}
 
@@ -159,15 +427,86 @@ impl ContextBuilder {
 		self
 	}
 
-	/// Adds basic networking. Compatible with Windows out-of-the box, but incurs high CPU overhead and wonky performance.
+	/// Adds basic networking. Compatible with Windows out-of-the box, but incurs high CPU overhead and wonky performance.  
+	/// All networking methods add another NIC when called multiple times. Options for user networking apply to the first one.
 	pub fn user_networking(&mut self) -> &mut Self {
-		self.networking = Networking::User;
-		self
+		self.add_nic(Networking::User)
 	}
 
 	/// Adds VirtIO networking. Less overhead, more stable, but requires driver installation on guest.
 	pub fn vfio_user_networking(&mut self) -> &mut Self {
-		self.networking = Networking::VirtioUser;
+		self.add_nic(Networking::VirtioUser)
+	}
+
+	/// Forwards `host_port` on all host addresses to `guest_port`, e.g. for RDP or SSH. Requires user networking.
+	pub fn port_forward(&mut self, protocol: Protocol, host_port: u16, guest_port: u16) -> &mut Self {
+		self.user_net.forwards.push(PortForward {
+			protocol,
+			host_port,
+			guest_port,
+		});
+
+		self
+	}
+
+	/// Forwards every port in `ports` to the same port on the guest. Requires user networking.
+	pub fn port_forward_range(&mut self, protocol: Protocol, ports: RangeInclusive<u16>) -> &mut Self {
+		for port in ports {
+			self.port_forward(protocol, port, port);
+		}
+
+		self
+	}
+
+	/// Shares `path` with the guest through QEMU's built-in SMB server, reachable at `\\10.0.2.4\qemu`.
+	/// Requires user networking and samba to be installed on the host.
+	pub fn smb_share(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+		self.user_net.smb = Some(path.into());
+		self
+	}
+
+	/// Sets the guest subnet in CIDR notation (default `10.0.2.0/24`). Requires user networking.
+	pub fn guest_subnet(&mut self, subnet: impl Into<String>) -> &mut Self {
+		self.user_net.net = Some(subnet.into());
+		self
+	}
+
+	/// Sets the address of the virtual DNS server (default `10.0.2.3`). Requires user networking.
+	pub fn guest_dns(&mut self, address: impl Into<String>) -> &mut Self {
+		self.user_net.dns = Some(address.into());
+		self
+	}
+
+	/// Attaches the VM to the existing bridge `bridge` through a TAP device, making it a regular member of the network.
+	/// The TAP device is created before and removed after running the VM.
+	pub fn bridge_networking(&mut self, bridge: impl Into<String>, model: NicModel) -> &mut Self {
+		self.add_nic(Networking::Bridge {
+			bridge: bridge.into(),
+			model,
+		})
+	}
+
+	/// Attaches the VM to the network of the host interface `parent` through a macvtap device, no bridge required.
+	/// The guest can't reach the host this way. The macvtap device is created before and removed after running the VM.
+	pub fn macvtap_networking(&mut self, parent: impl Into<String>, model: NicModel) -> &mut Self {
+		self.add_nic(Networking::Macvtap {
+			parent: parent.into(),
+			model,
+		})
+	}
+
+	/// Adds user-mode networking through [passt](https://passt.top), which performs a lot better than the built-in networking.
+	/// Port forwards apply, SMB share, guest subnet and DNS don't. passt is started before and stopped after running the VM.
+	pub fn passt_networking(&mut self, model: NicModel) -> &mut Self {
+		self.add_nic(Networking::Passt { model })
+	}
+
+	/// Sets the MAC address of the most recently added NIC. See [`MacAddress`] for the available modes.
+	pub fn mac_address(&mut self, mac: MacAddress) -> &mut Self {
+		self.nics
+			.last_mut()
+			.expect("network should be added before configuring it")
+			.mac = mac;
 		self
 	}
 
@@ -179,7 +518,31 @@ impl ContextBuilder {
 
 	/// Passes the specified PCI devices through to the VM; automatically un- and rebinds devices.
 	pub fn pci_device(&mut self, address: impl Into<String>) -> &mut Self {
-		self.pci.push(address.into());
+		self.pci_entry(address.into());
+		self
+	}
+
+	/// Makes the guest load the option ROM (VBIOS) of the specified PCI device from `path` instead of the device.
+	/// Some cards need a patched ROM to initialise in the guest, see `vfio-run dump-rom`.  
+	/// Also passes the device through if it wasn't already.
+	pub fn pci_romfile(&mut self, address: impl Into<String>, path: impl Into<PathBuf>) -> &mut Self {
+		self.pci_entry(address.into()).romfile = Some(path.into());
+		self
+	}
+
+	/// Sets the reset methods the kernel may use for the specified PCI device before detaching it,
+	/// e.g. `"bus"` or `"device_specific flr"`. See `reset_method` in [sysfs-bus-pci](https://www.kernel.org/doc/Documentation/ABI/testing/sysfs-bus-pci).  
+	/// Also passes the device through if it wasn't already.
+	pub fn pci_reset_method(&mut self, address: impl Into<String>, method: impl Into<String>) -> &mut Self {
+		self.pci_entry(address.into()).reset_method = Some(method.into());
+		self
+	}
+
+	/// Explicitly resets the specified PCI device before rebinding it to the host.
+	/// Helps with cards that come back broken after running the VM.  
+	/// Also passes the device through if it wasn't already.
+	pub fn pci_reset(&mut self, address: impl Into<String>, reset: PciReset) -> &mut Self {
+		self.pci_entry(address.into()).reset = Some(reset);
 		self
 	}
 
@@ -199,6 +562,28 @@ impl ContextBuilder {
 		self
 	}
 
+	/// Unbinds the host's framebuffer consoles and the EFI/simple framebuffer before detaching devices,
+	/// and rebinds exactly those afterwards. Helps with getting the TTY back in single-GPU setups.
+	pub fn release_framebuffer(&mut self) -> &mut Self {
+		self.release_framebuffer = true;
+		self
+	}
+
+	/// Stops the specified systemd unit (e.g. `display-manager.service`) before detaching devices
+	/// and waits for its clients to let go of the GPU. The unit is started again after reattaching.  
+	/// Allows starting the VM from the graphical session.
+	pub fn display_manager(&mut self, unit: impl Into<String>) -> &mut Self {
+		self.display_manager = Some(unit.into());
+		self
+	}
+
+	/// Offers to terminate processes still using the passed-through GPUs, after asking for confirmation.  
+	/// By default, they are only listed and detaching is aborted.
+	pub fn terminate_gpu_holders(&mut self) -> &mut Self {
+		self.terminate_gpu_holders = true;
+		self
+	}
+
 	/// Adds a virtual graphics device.
 	pub fn vga(&mut self, vga: Vga) -> &mut Self {
 		self.vga = vga;
@@ -236,23 +621,78 @@ impl ContextBuilder {
 		self
 	}
 
-	pub fn build(self) -> Context {
+	pub fn build(mut self) -> Context {
 		let mut arg_writer = ArgWriter::default();
 		let mut env_writer = EnvWriter::default();
 		let mut tmp_file_writer = TmpFileWriter::default();
+		let mut net_devices = vec![];
+		let mut daemons = vec![];
+
+		if let Ephemeral::Discard | Ephemeral::Keep = self.ephemeral {
+			self.add_ephemeral_overlays();
+		}
+
+		if let (true, Some(answers)) = (self.installing, &self.unattend) {
+			let uefi = self.bios_type.is_uefi();
+			let iso = unattend::build_iso(answers, uefi);
+
+			// the answer file contains account passwords
+			tmp_file_writer.add_private(UNATTEND_ISO, iso);
+			self.cdroms.push(PathBuf::from(UNATTEND_ISO));
+		}
+
+		let forwards = match self.nics.iter().any(|n| n.networking.is_user_mode()) {
+			true => self.user_net.forwards.clone(),
+			false => vec![],
+		};
+
+		let vcpu_pins = self.apply_auto_topology();
+		let placement = self.get_numa_placement(&vcpu_pins);
+		let tpm_state = self.profile_dir().join("tpm");
+		let nvram = self.get_nvram();
+		let mut structures = self.get_smbios_structures();
+
+		if self.smbios_randomize {
+			// a seed that isn't random would hand every guest the same identity
+			match rng::load_seed(&self.profile_dir().join("smbios-seed")) {
+				Ok(seed) => identity::randomize(&mut self.smbios, &mut structures, seed),
+				Err(e) => log::error!("unable to generate smbios seed, not randomizing: {e}"),
+			}
+		}
 
 		build::add_defaults(&mut arg_writer);
+		if !vcpu_pins.is_empty() {
+			build::add_name(&mut arg_writer, &self.profile);
+		}
 		build::add_monitor(&mut arg_writer);
-		build::add_system(&mut arg_writer, self.cpu, self.smp, self.ram);
-		build::add_bios(&mut arg_writer, self.bios_type);
-		build::add_smbios(&mut arg_writer, self.smbios);
+		let shared_memory = !self.shared_folders.is_empty();
+		build::add_system(
+			&mut arg_writer,
+			self.cpu,
+			self.smp,
+			self.ram,
+			shared_memory,
+			placement.as_ref(),
+		);
+		build::add_bios(&mut arg_writer, self.bios_type, nvram.as_ref());
+		build::add_smbios(&mut arg_writer, &mut tmp_file_writer, self.smbios, structures);
 		build::add_vga(&mut arg_writer, self.vga);
 		build::add_window(&mut arg_writer, self.window);
 		build::add_audio_backend(&mut arg_writer, &mut env_writer, self.audio_backend);
 		build::add_audio_frontend(&mut arg_writer, self.audio_frontend);
-		build::add_networking(&mut arg_writer, self.networking);
+		build::add_networking(
+			&mut arg_writer,
+			&mut net_devices,
+			self.nics,
+			&self.user_net,
+			&self.profile,
+		);
+		build::add_tpm(&mut arg_writer, &mut daemons, self.tpm, tpm_state);
+		build::add_shared_folders(&mut arg_writer, &mut daemons, self.shared_folders);
 		build::add_pci(&mut arg_writer, &self.pci);
-		build::add_disks(&mut arg_writer, self.disks);
+		build::add_disks(&mut arg_writer, &self.disks, &self.boot_order);
+		build::add_cdroms(&mut arg_writer, &self.cdroms, &self.boot_order);
+		build::add_boot(&mut arg_writer, self.boot_menu);
 		build::add_usb(&mut arg_writer, self.usb);
 		build::add_looking_glass(&mut arg_writer, &mut tmp_file_writer, self.looking_glass);
 		build::add_spice(&mut arg_writer, self.spice);
@@ -264,9 +704,202 @@ impl ContextBuilder {
 			pci: self.pci,
 			pat_dealloc: self.pat_dealloc,
 			unload_drivers: self.unload_drivers,
+			release_framebuffer: self.release_framebuffer,
+			display_manager: self.display_manager,
+			terminate_gpu_holders: self.terminate_gpu_holders,
 			tmp_files: tmp_file_writer.get_tmp_files(),
+			overlays: get_overlays(&self.disks, matches!(self.ephemeral, Ephemeral::Discard)),
+			images: get_images(&self.disks),
+			net_devices,
+			daemons,
+			nvram,
+			forwards,
 			cpu_affinity: self.cpu_affinity,
 			cpu_governor: self.cpu_governor,
+			vcpu_pins,
+		}
+	}
+
+	// falls back to the manual settings when the host's topology can't be used
+	fn apply_auto_topology(&mut self) -> Vec<usize> {
+		let layout = match (self.auto_topology, &self.cpu_affinity) {
+			(None, _) => return vec![],
+			(Some(AutoTopology::Cores(cores)), _) => topology::from_core_count(cores),
+			(Some(AutoTopology::Affinity), Some(affinity)) => topology::from_affinity(affinity),
+			(Some(AutoTopology::Affinity), None) => {
+				log::error!("deriving cpu topology from the affinity requires cpu_affinity to be set");
+				return vec![];
+			}
+		};
+
+		match layout {
+			Ok(layout) => {
+				log::info!("using -smp {} on host cpus {}", layout.smp, layout.affinity());
+
+				self.cpu_affinity = Some(layout.affinity());
+				self.smp = Some(layout.smp);
+				layout.pins
+			}
+			Err(e) => {
+				log::error!("unable to derive cpu topology, using smp and cpu_affinity as is: {e}");
+				vec![]
+			}
 		}
 	}
+
+	fn get_numa_placement(&self, vcpu_pins: &[usize]) -> Option<numa::Placement> {
+		let numa = self.numa?;
+		let affinity = self.cpu_affinity.as_deref();
+
+		match numa::place(numa, affinity, vcpu_pins, &self.pci, &self.ram) {
+			Ok(placement) => placement,
+			Err(e) => {
+				log::error!("unable to read numa topology, not binding guest memory: {e}");
+				None
+			}
+		}
+	}
+
+	fn profile_dir(&self) -> PathBuf {
+		Path::new(STATE_DIR).join(&self.profile)
+	}
+
+	// structures from the file take precedence over those built from the host
+	fn get_smbios_structures(&self) -> Vec<dmi::Structure> {
+		let mut structures = self.smbios_file.as_deref().map(load_smbios_file).unwrap_or_default();
+
+		if self.smbios_structures {
+			let mut host = smbios::populate_structures(&self.ram, self.smp.as_deref());
+			smbios::apply_fields(&mut host, &self.smbios);
+
+			let mut kinds = structures.iter().map(|s| s.kind).collect::<Vec<_>>();
+
+			// DIMMs refer to their memory array by handle, both have to come from the same source
+			if kinds.contains(&dmi::PHYSICAL_MEMORY_ARRAY) || kinds.contains(&dmi::MEMORY_DEVICE) {
+				kinds.extend([dmi::PHYSICAL_MEMORY_ARRAY, dmi::MEMORY_DEVICE]);
+			}
+
+			structures.extend(host.into_iter().filter(|s| !kinds.contains(&s.kind)));
+		}
+
+		structures
+	}
+
+	fn get_nvram(&self) -> Option<Nvram> {
+		let BiosType::OvmfPflash { vars_template, .. } = &self.bios_type else {
+			return None;
+		};
+
+		let name = vars_template.file_name().unwrap_or_else(|| "OVMF_VARS.fd".as_ref());
+
+		Some(Nvram {
+			path: self.profile_dir().join(name),
+			template: vars_template.clone(),
+		})
+	}
+
+	fn add_nic(&mut self, networking: Networking) -> &mut Self {
+		self.nics.push(Nic {
+			networking,
+			mac: MacAddress::Default,
+		});
+
+		self
+	}
+
+	fn add_image(&mut self, path: PathBuf, options: DiskOptions) -> &mut Self {
+		let format = match options.format {
+			Some(format) => Some(format),
+			// whatever the guest wrote to the start of a physical disk must not change how it's opened
+			None if disk::is_block_device(&path) => Some(DiskFormat::Raw),
+			None => disk::detect_image_format(&path),
+		};
+
+		match format {
+			Some(format) => self.add_disk(path, format, options),
+			None => {
+				log::error!("not attaching {}", path.display());
+				self
+			}
+		}
+	}
+
+	fn add_disk(&mut self, path: PathBuf, format: DiskFormat, options: DiskOptions) -> &mut Self {
+		self.disks.push(Disk {
+			path,
+			format,
+			options,
+			overlay: None,
+		});
+
+		self
+	}
+
+	// replaces any configured overlays, those would be recreated anyway
+	fn add_ephemeral_overlays(&mut self) {
+		for (index, disk) in self.disks.iter_mut().enumerate() {
+			let name = disk.path.file_name().unwrap_or_default().to_string_lossy();
+			disk.overlay = Some(self.overlay_dir.join(format!("{index}-{name}.qcow2")));
+		}
+	}
+
+	// refused images aren't added, configuring them is an error rather than a mistake in the config
+	fn disk_entry(&mut self, path: &Path) -> Option<&mut Disk> {
+		let disk = self.disks.iter_mut().find(|d| d.path == path);
+
+		if disk.is_none() {
+			log::error!("no disk {} to configure", path.display());
+		}
+
+		disk
+	}
+
+	fn pci_entry(&mut self, address: String) -> &mut PciDevice {
+		let index = match self.pci.iter().position(|d| d.address == address) {
+			Some(index) => index,
+			None => {
+				self.pci.push(PciDevice {
+					address,
+					romfile: None,
+					reset_method: None,
+					reset: None,
+				});
+				self.pci.len() - 1
+			}
+		};
+
+		&mut self.pci[index]
+	}
+}
+
+fn get_overlays(disks: &[Disk], discard: bool) -> Vec<Overlay> {
+	disks
+		.iter()
+		.filter_map(|disk| {
+			Some(Overlay {
+				path: disk.overlay.clone()?,
+				backing_file: disk.path.clone(),
+				backing_format: disk.format,
+				discard,
+			})
+		})
+		.collect()
+}
+
+fn load_smbios_file(path: &Path) -> Vec<dmi::Structure> {
+	match fs::read(path) {
+		Ok(table) => dmi::split(&table),
+		Err(e) => {
+			log::error!("unable to read smbios file {}: {e}", path.display());
+			vec![]
+		}
+	}
+}
+
+fn get_images(disks: &[Disk]) -> Vec<PathBuf> {
+	disks
+		.iter()
+		.filter(|d| d.format == DiskFormat::Qcow2)
+		.map(|d| d.path.clone())
+		.collect()
 }
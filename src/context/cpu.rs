@@ -0,0 +1,139 @@
+use super::{CpuOptions, HyperV};
+
+const VENDOR_LENGTH: usize = 12;
+const MIN_SPINLOCKS: u32 = 0xfff;
+
+/// Renders the options into a `-cpu` string.
+pub fn render(options: &CpuOptions) -> String {
+	let mut parts = vec![options.model.clone()];
+
+	if let Some(vendor) = &options.vendor {
+		parts.push(format!("vendor={vendor}"));
+	}
+
+	if options.hide_kvm {
+		parts.push(String::from("kvm=off"));
+	}
+
+	parts.extend(options.hyperv.iter().map(|&e| enlightenment(e)));
+
+	if let Some(vendor_id) = &options.hv_vendor_id {
+		parts.push(format!("hv-vendor-id={vendor_id}"));
+	}
+
+	parts.extend(options.enable.iter().map(|f| format!("{f}=on")));
+	parts.extend(options.disable.iter().map(|f| format!("{f}=off")));
+
+	parts.join(",")
+}
+
+/// Drops options QEMU would reject or silently ignore, returning why each was dropped.
+pub fn sanitize(options: &mut CpuOptions) -> Vec<String> {
+	let mut dropped = vec![];
+
+	if let Some(vendor) = options.vendor.take_if(|v| v.len() != VENDOR_LENGTH) {
+		dropped.push(format!("vendor {vendor} must be exactly {VENDOR_LENGTH} characters"));
+	}
+
+	if let Some(vendor_id) = options.hv_vendor_id.take_if(|v| v.len() > VENDOR_LENGTH) {
+		dropped.push(format!(
+			"hv_vendor_id {vendor_id} is longer than {VENDOR_LENGTH} characters"
+		));
+	}
+
+	let conflicting = options
+		.enable
+		.iter()
+		.filter(|f| options.disable.contains(f))
+		.cloned()
+		.collect::<Vec<_>>();
+
+	for feature in conflicting {
+		options.enable.retain(|f| *f != feature);
+		options.disable.retain(|f| *f != feature);
+		dropped.push(format!("feature {feature} is both enabled and disabled"));
+	}
+
+	if options.hide_kvm && options.enable.iter().any(|f| f == "kvm") {
+		options.enable.retain(|f| f != "kvm");
+		dropped.push(String::from("feature kvm is enabled, but kvm is also hidden"));
+	}
+
+	for features in [&mut options.enable, &mut options.disable] {
+		features.retain(|feature| {
+			let keep = !feature.starts_with("hv");
+
+			if !keep {
+				dropped.push(format!("feature {feature} should be set through hyperv instead"));
+			}
+
+			keep
+		});
+	}
+
+	options.hyperv.retain(|&enlightenment| {
+		let HyperV::Spinlocks(retries @ 0..MIN_SPINLOCKS) = enlightenment else {
+			return true;
+		};
+
+		dropped.push(format!(
+			"hv-spinlocks={retries:#x} is below the minimum of {MIN_SPINLOCKS:#x}"
+		));
+		false
+	});
+
+	// dropping one enlightenment can leave others depending on it unsatisfied
+	while let Some((index, required)) = options.hyperv.iter().enumerate().find_map(|(index, &enlightenment)| {
+		let required = requirements(enlightenment)
+			.iter()
+			.find(|r| !options.hyperv.contains(r))?;
+		Some((index, *required))
+	}) {
+		let name = enlightenment(options.hyperv.remove(index));
+		dropped.push(format!("{name} requires {}", enlightenment(required)));
+	}
+
+	if options.hyperv.is_empty() {
+		if let Some(vendor_id) = options.hv_vendor_id.take() {
+			dropped.push(format!(
+				"hv_vendor_id {vendor_id} has no effect without hyper-v enlightenments"
+			));
+		}
+	}
+
+	dropped
+}
+
+fn enlightenment(enlightenment: HyperV) -> String {
+	let name = match enlightenment {
+		HyperV::Relaxed => "hv-relaxed",
+		HyperV::Vapic => "hv-vapic",
+		HyperV::Spinlocks(retries) => return format!("hv-spinlocks={retries:#x}"),
+		HyperV::Vpindex => "hv-vpindex",
+		HyperV::Runtime => "hv-runtime",
+		HyperV::Synic => "hv-synic",
+		HyperV::Stimer => "hv-stimer",
+		HyperV::StimerDirect => "hv-stimer-direct",
+		HyperV::Reset => "hv-reset",
+		HyperV::Frequencies => "hv-frequencies",
+		HyperV::Time => "hv-time",
+		HyperV::Reenlightenment => "hv-reenlightenment",
+		HyperV::TlbFlush => "hv-tlbflush",
+		HyperV::Ipi => "hv-ipi",
+		HyperV::Evmcs => "hv-evmcs",
+		HyperV::Avic => "hv-avic",
+	};
+
+	String::from(name)
+}
+
+// Dependencies QEMU enforces, see `kvm_hyperv_properties` in target/i386/kvm/kvm.c
+fn requirements(enlightenment: HyperV) -> &'static [HyperV] {
+	match enlightenment {
+		HyperV::Synic | HyperV::TlbFlush | HyperV::Ipi => &[HyperV::Vpindex],
+		HyperV::Stimer => &[HyperV::Synic, HyperV::Time],
+		HyperV::StimerDirect => &[HyperV::Stimer],
+		HyperV::Evmcs => &[HyperV::Vapic],
+		_ => &[],
+	}
+}
@@ -0,0 +1,244 @@
+use super::{DiskFormat, DiskIdentity};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{self, Path, PathBuf};
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const VMDK_SPARSE_MAGIC: &[u8] = b"KDMV";
+const VMDK_DESCRIPTOR_MAGIC: &[u8] = b"# Disk DescriptorFile";
+
+const QCOW2_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const QCOW2_MAX_BACKING_FILE_SIZE: u32 = 1023;
+const VMDK_SECTOR_SIZE: u64 = 512;
+const VMDK_MAX_DESCRIPTOR_SIZE: u64 = 64 * 1024;
+
+/// Determines the image format from the file header, anything unrecognized is treated as raw.
+pub fn detect_format(path: &Path) -> DiskFormat {
+	let mut header = Vec::with_capacity(VMDK_DESCRIPTOR_MAGIC.len());
+
+	let result = File::open(path).and_then(|f| f.take(VMDK_DESCRIPTOR_MAGIC.len() as u64).read_to_end(&mut header));
+
+	if let Err(e) = result {
+		log::warn!("unable to read header of {}, assuming raw: {e}", path.display());
+		return DiskFormat::Raw;
+	}
+
+	let format = match header {
+		h if h.starts_with(QCOW2_MAGIC) => DiskFormat::Qcow2,
+		h if h.starts_with(VMDK_SPARSE_MAGIC) || h.starts_with(VMDK_DESCRIPTOR_MAGIC) => DiskFormat::Vmdk,
+		_ => DiskFormat::Raw,
+	};
+
+	log::debug!("detected {} as {}", path.display(), format.as_str());
+	format
+}
+
+/// Detects the format of an image file like [`detect_format`], but refuses images that make QEMU open other files.
+/// A guest can write any header to a raw image, which would make the next run read whatever file it names.
+/// Backing files created by external snapshots are allowed, see [`external_snapshot`].
+pub fn detect_image_format(path: &Path) -> Option<DiskFormat> {
+	let format = detect_format(path);
+
+	match external_file(path, format) {
+		Ok(None) => Some(format),
+		Ok(Some(file)) => {
+			log::error!(
+				"{} is detected as {} referring to {file}, refusing to open it without its format set in DiskOptions",
+				path.display(),
+				format.as_str()
+			);
+			None
+		}
+		Err(e) => {
+			log::error!("unable to read header of {}: {e}", path.display());
+			None
+		}
+	}
+}
+
+/// External snapshots of `image` sit next to it, named `<image>.<snapshot>`.
+pub fn external_snapshot(image: &Path, name: &str) -> PathBuf {
+	let file = image.file_name().unwrap_or_default().to_string_lossy();
+	image.with_file_name(format!("{file}.{name}"))
+}
+
+// The first file outside the image QEMU would open
+fn external_file(path: &Path, format: DiskFormat) -> io::Result<Option<String>> {
+	match format {
+		DiskFormat::Raw => Ok(None),
+		DiskFormat::Qcow2 => qcow2_external_file(path),
+		// descriptor files only list extents, which are always other files
+		DiskFormat::Vmdk if !is_sparse_vmdk(path)? => Ok(Some(String::from("its extents"))),
+		DiskFormat::Vmdk => vmdk_external_file(path),
+	}
+}
+
+fn qcow2_external_file(path: &Path) -> io::Result<Option<String>> {
+	let mut file = File::open(path)?;
+	let mut header = [0; 80];
+	file.read_exact(&mut header)?;
+
+	let version = u32::from_be_bytes(header[4..8].try_into().unwrap_or_default());
+	let backing_file_offset = u64::from_be_bytes(header[8..16].try_into().unwrap_or_default());
+	let backing_file_size = u32::from_be_bytes(header[16..20].try_into().unwrap_or_default());
+
+	// version 2 headers end before the feature bits
+	let incompatible_features = match version {
+		3.. => u64::from_be_bytes(header[72..80].try_into().unwrap_or_default()),
+		_ => 0,
+	};
+
+	if incompatible_features & QCOW2_EXTERNAL_DATA_FILE != 0 {
+		return Ok(Some(String::from("an external data file")));
+	}
+
+	if backing_file_offset == 0 {
+		return Ok(None);
+	}
+
+	let mut name = vec![0; backing_file_size.min(QCOW2_MAX_BACKING_FILE_SIZE) as usize];
+	file.seek(SeekFrom::Start(backing_file_offset))?;
+	file.read_exact(&mut name)?;
+
+	let name = String::from_utf8_lossy(&name).into_owned();
+	Ok((!is_external_snapshot(path, Path::new(&name))).then_some(name))
+}
+
+fn is_sparse_vmdk(path: &Path) -> io::Result<bool> {
+	let mut magic = [0; VMDK_SPARSE_MAGIC.len()];
+	File::open(path)?.read_exact(&mut magic)?;
+
+	Ok(magic == VMDK_SPARSE_MAGIC)
+}
+
+// Sparse VMDKs embed a descriptor, which names the parent of a delta disk
+fn vmdk_external_file(path: &Path) -> io::Result<Option<String>> {
+	let mut file = File::open(path)?;
+	let mut header = [0; 44];
+	file.read_exact(&mut header)?;
+
+	let descriptor_offset = u64::from_le_bytes(header[28..36].try_into().unwrap_or_default());
+	let descriptor_size = u64::from_le_bytes(header[36..44].try_into().unwrap_or_default());
+
+	if descriptor_offset == 0 {
+		return Ok(None);
+	}
+
+	let mut descriptor = vec![];
+	file.seek(SeekFrom::Start(descriptor_offset.saturating_mul(VMDK_SECTOR_SIZE)))?;
+	file.take(
+		descriptor_size
+			.saturating_mul(VMDK_SECTOR_SIZE)
+			.min(VMDK_MAX_DESCRIPTOR_SIZE),
+	)
+	.read_to_end(&mut descriptor)?;
+
+	let parent = String::from_utf8_lossy(&descriptor)
+		.lines()
+		.find_map(|l| l.trim().strip_prefix("parentFileNameHint"))
+		.map(|hint| hint.trim_start_matches([' ', '=']).trim_matches('"').to_owned());
+
+	Ok(parent)
+}
+
+// Relative backing files are resolved relative to the image, `..` is left in place and never matches
+fn is_external_snapshot(image: &Path, backing_file: &Path) -> bool {
+	let backing_file = image
+		.parent()
+		.map_or_else(|| backing_file.to_path_buf(), |dir| dir.join(backing_file));
+	let (Ok(image), Ok(backing_file)) = (path::absolute(image), path::absolute(backing_file)) else {
+		return false;
+	};
+
+	let (Some(image_name), Some(backing_name)) = (image.file_name(), backing_file.file_name()) else {
+		return false;
+	};
+
+	let prefix = format!("{}.", image_name.to_string_lossy());
+	let backing_name = backing_name.to_string_lossy();
+
+	image.parent() == backing_file.parent() && backing_name.len() > prefix.len() && backing_name.starts_with(&prefix)
+}
+
+/// Physical disks are opened as host devices, which QEMU handles differently from regular files.
+pub fn is_block_device(path: &Path) -> bool {
+	fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+/// Reads serial number, WWN, vendor and model of a physical disk from sysfs and `/dev/disk/by-id`.
+/// Fields that can't be determined are left unset.
+pub fn read_identity(path: &Path) -> DiskIdentity {
+	let Ok(device) = fs::canonicalize(path) else {
+		log::warn!("unable to resolve {}, cannot retrieve disk identity", path.display());
+		return DiskIdentity::default();
+	};
+
+	let Some(sysfs) = sysfs_device(&device) else {
+		log::warn!("no sysfs entry for {}, cannot retrieve disk identity", device.display());
+		return DiskIdentity::default();
+	};
+
+	// by-id names of a partition end in -partN and have no WWN, only those of the whole disk are usable
+	let disk = sysfs
+		.file_name()
+		.map_or_else(|| device.clone(), |name| Path::new("/dev").join(name));
+	let by_id = by_id_names(&disk);
+
+	// NVMe namespaces keep serial and model on the controller, SCSI devices have the model only
+	let identity = DiskIdentity {
+		serial: read_attribute(&sysfs, "device/serial").or_else(|| serial_from_by_id(&by_id)),
+		wwn: by_id.iter().find_map(|n| parse_wwn(n)),
+		vendor: read_attribute(&sysfs, "device/vendor"),
+		product: read_attribute(&sysfs, "device/model"),
+	};
+
+	log::debug!("read identity of {}: {identity:?}", disk.display());
+	identity
+}
+
+// Partitions don't have identities of their own, use the disk they're on
+fn sysfs_device(device: &Path) -> Option<PathBuf> {
+	let name = device.file_name()?;
+	let sysfs = fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+
+	match sysfs.join("partition").exists() {
+		true => sysfs.parent().map(Path::to_path_buf),
+		false => Some(sysfs),
+	}
+}
+
+fn by_id_names(device: &Path) -> Vec<String> {
+	let Ok(entries) = fs::read_dir("/dev/disk/by-id") else {
+		return vec![];
+	};
+
+	entries
+		.filter_map(Result::ok)
+		.filter(|e| fs::canonicalize(e.path()).is_ok_and(|target| target == device))
+		.map(|e| e.file_name().to_string_lossy().into_owned())
+		.collect()
+}
+
+// e.g. ata-Samsung_SSD_860_EVO_1TB_S3Z9NB0K123456A, the serial is the last segment
+fn serial_from_by_id(names: &[String]) -> Option<String> {
+	names
+		.iter()
+		.filter(|n| n.starts_with("ata-") || n.starts_with("scsi-") || n.starts_with("nvme-"))
+		.filter(|n| !n.contains("-part"))
+		.find_map(|n| n.rsplit_once('_'))
+		.map(|(_, serial)| serial.to_owned())
+}
+
+// 128 bit names (e.g. NVMe EUIs) don't fit the 64 bit WWN QEMU supports
+fn parse_wwn(name: &str) -> Option<u64> {
+	let hex = name.strip_prefix("wwn-0x")?;
+	u64::from_str_radix(hex, 16).ok()
+}
+
+fn read_attribute(sysfs: &Path, attribute: &str) -> Option<String> {
+	let value = fs::read_to_string(sysfs.join(attribute)).ok()?;
+	let value = value.trim();
+
+	(!value.is_empty()).then(|| value.to_owned())
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::io;
+
+const HOST_TABLE: &str = "/sys/firmware/dmi/tables/DMI";
+const HEADER_LENGTH: usize = 4;
+const HANDLE_OFFSET: usize = 0x02;
+const UUID_OFFSET: usize = 0x08;
+
+pub const SYSTEM_INFORMATION: u8 = 1;
+pub const PROCESSOR: u8 = 4;
+pub const PORT_CONNECTOR: u8 = 8;
+pub const SYSTEM_SLOT: u8 = 9;
+pub const PHYSICAL_MEMORY_ARRAY: u8 = 16;
+pub const MEMORY_DEVICE: u8 = 17;
+pub const END_OF_TABLE: u8 = 127;
+
+/// A single structure, consisting of the formatted area and the string set following it.
+#[derive(Clone, Debug)]
+pub struct Structure {
+	pub kind: u8,
+	pub bytes: Vec<u8>,
+}
+
+impl Structure {
+	/// Length of the formatted area, fields beyond it don't exist in this version of the structure.
+	fn length(&self) -> usize {
+		usize::from(self.bytes[1])
+	}
+
+	pub fn byte(&self, offset: usize) -> Option<u8> {
+		(offset < self.length()).then(|| self.bytes[offset])
+	}
+
+	pub fn word(&self, offset: usize) -> Option<u16> {
+		(offset + 2 <= self.length()).then(|| u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]]))
+	}
+
+	/// Fields missing from the structure are left out.
+	pub fn set_byte(&mut self, offset: usize, value: u8) {
+		if offset < self.length() {
+			self.bytes[offset] = value;
+		}
+	}
+
+	pub fn set_word(&mut self, offset: usize, value: u16) {
+		self.set_bytes(offset, &value.to_le_bytes());
+	}
+
+	pub fn set_dword(&mut self, offset: usize, value: u32) {
+		self.set_bytes(offset, &value.to_le_bytes());
+	}
+
+	pub fn set_qword(&mut self, offset: usize, value: u64) {
+		self.set_bytes(offset, &value.to_le_bytes());
+	}
+
+	pub fn set_handle(&mut self, handle: u16) {
+		self.set_word(HANDLE_OFFSET, handle);
+	}
+
+	/// Returns the string referenced by the field at `offset`, `None` when it's missing or unset.
+	pub fn string(&self, offset: usize) -> Option<&str> {
+		let index = usize::from(self.byte(offset)?.checked_sub(1)?);
+		let bytes = self.strings().nth(index)?;
+
+		std::str::from_utf8(bytes).ok()
+	}
+
+	/// Points the field at `offset` to a new string. The old one stays, other fields may refer to it as well.
+	pub fn set_string(&mut self, offset: usize, value: &str) {
+		let count = self.strings().count();
+
+		// an empty string would end the string set, and 255 is the most a field can refer to
+		if self.byte(offset).is_none() || value.is_empty() || count >= usize::from(u8::MAX) {
+			return;
+		}
+
+		let mut strings = self.strings().map(<[u8]>::to_vec).collect::<Vec<_>>();
+		strings.push(value.as_bytes().to_vec());
+
+		self.bytes.truncate(self.length());
+
+		for string in strings {
+			self.bytes.extend(string);
+			self.bytes.push(0);
+		}
+
+		self.bytes.push(0);
+		self.set_byte(offset, (count + 1) as u8);
+	}
+
+	fn strings(&self) -> impl Iterator<Item = &[u8]> {
+		self.bytes[self.length()..]
+			.split(|&b| b == 0)
+			.take_while(|s| !s.is_empty())
+	}
+
+	fn set_bytes(&mut self, offset: usize, value: &[u8]) {
+		if offset + value.len() <= self.length() {
+			self.bytes[offset..offset + value.len()].copy_from_slice(value);
+		}
+	}
+}
+
+/// Reads the host's structures from sysfs.
+pub fn read_host() -> io::Result<Vec<Structure>> {
+	fs::read(HOST_TABLE).map(|table| split(&table))
+}
+
+/// Splits a raw structure table, e.g. `/sys/firmware/dmi/tables/DMI`, up to the end-of-table structure.  
+/// QEMU appends an end-of-table structure of its own, so it is left out.
+pub fn split(mut table: &[u8]) -> Vec<Structure> {
+	let mut structures = vec![];
+
+	while table.len() >= HEADER_LENGTH {
+		let kind = table[0];
+		let length = usize::from(table[1]);
+
+		// the string set ends with a double null, even when empty
+		let Some(end) = table
+			.get(length..)
+			.and_then(|strings| strings.windows(2).position(|w| w == [0, 0]))
+		else {
+			log::warn!("truncated smbios structure of type {kind}, ignoring the rest of the table");
+			break;
+		};
+
+		if kind == END_OF_TABLE {
+			break;
+		}
+
+		let (structure, rest) = table.split_at(length + end + 2);
+		structures.push(Structure {
+			kind,
+			bytes: structure.to_vec(),
+		});
+
+		table = rest;
+	}
+
+	structures
+}
+
+/// Replaces the UUID of a system information structure. Returns false when the structure has none.
+pub fn set_uuid(structure: &mut Structure, uuid: &str) -> bool {
+	let Some(mut bytes) = parse_uuid(uuid) else {
+		return false;
+	};
+
+	if structure.kind != SYSTEM_INFORMATION || structure.length() < UUID_OFFSET + bytes.len() {
+		return false;
+	}
+
+	// since SMBIOS 2.6, the first three fields are little endian
+	bytes[..4].reverse();
+	bytes[4..6].reverse();
+	bytes[6..8].reverse();
+
+	structure.bytes[UUID_OFFSET..UUID_OFFSET + bytes.len()].copy_from_slice(&bytes);
+	true
+}
+
+fn parse_uuid(uuid: &str) -> Option<[u8; 16]> {
+	let hex = uuid.replace('-', "");
+
+	if hex.len() != 32 {
+		return None;
+	}
+
+	let bytes = (0..16)
+		.map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+		.collect::<Option<Vec<_>>>()?;
+
+	bytes.try_into().ok()
+}
@@ -0,0 +1,144 @@
+use super::{BiosType, OvmfFeatures};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+// Descriptors in /etc override those of the same name in /usr/share, the file names define the priority
+const DESCRIPTOR_DIRS: [&str; 2] = ["/usr/share/qemu/firmware", "/etc/qemu/firmware"];
+
+/// Firmware descriptor as specified in QEMU's `docs/interop/firmware.json`, only the parts relevant here.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Descriptor {
+	#[serde(default)]
+	interface_types: Vec<String>,
+	mapping: Mapping,
+	#[serde(default)]
+	targets: Vec<Target>,
+	#[serde(default)]
+	features: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Mapping {
+	device: String,
+	#[serde(default)]
+	mode: Option<String>,
+	executable: Option<FlashFile>,
+	nvram_template: Option<FlashFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FlashFile {
+	filename: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct Target {
+	architecture: String,
+	#[serde(default)]
+	machines: Vec<String>,
+}
+
+/// Picks the firmware best matching `wanted` from the installed descriptors.
+pub fn find_ovmf(wanted: OvmfFeatures) -> Option<BiosType> {
+	let (name, descriptor) = read_descriptors()
+		.into_iter()
+		.filter(|(_, d)| is_usable(d, wanted))
+		// max_by_key picks the last of equal elements, reversing keeps the highest priority one
+		.rev()
+		.max_by_key(|(_, d)| score(d, wanted))?;
+
+	let mapping = descriptor.mapping;
+	let code = mapping.executable?.filename;
+
+	log::info!("using firmware {} from {}", code.display(), name.to_string_lossy());
+
+	let bios = match (mapping.mode.as_deref(), mapping.nvram_template) {
+		(None | Some("split"), Some(template)) => BiosType::OvmfPflash {
+			code,
+			vars_template: template.filename,
+			secure_boot: wanted.secure_boot,
+		},
+		_ => BiosType::Ovmf(code),
+	};
+
+	Some(bios)
+}
+
+fn read_descriptors() -> BTreeMap<OsString, Descriptor> {
+	let mut descriptors = BTreeMap::new();
+
+	for entry in DESCRIPTOR_DIRS
+		.iter()
+		.filter_map(|d| fs::read_dir(d).ok())
+		.flatten()
+		.flatten()
+	{
+		let path = entry.path();
+
+		if path.extension().is_none_or(|e| e != "json") {
+			continue;
+		}
+
+		// empty files mask descriptors of the same name
+		let descriptor = fs::read(&path).ok().filter(|c| !c.is_empty()).and_then(|c| {
+			serde_json::from_slice::<Descriptor>(&c)
+				.inspect_err(|e| log::warn!("unable to parse {}: {e}", path.display()))
+				.ok()
+		});
+
+		match descriptor {
+			Some(descriptor) => descriptors.insert(entry.file_name(), descriptor),
+			None => descriptors.remove(&entry.file_name()),
+		};
+	}
+
+	descriptors
+}
+
+fn is_usable(descriptor: &Descriptor, wanted: OvmfFeatures) -> bool {
+	let has = |feature: &str| descriptor.features.iter().any(|f| f == feature);
+
+	// Secure Boot firmware refuses to boot without SMM, which in turn only works on q35
+	let machine = match wanted.secure_boot {
+		true => "pc-q35",
+		false => "pc-i440fx",
+	};
+
+	let supports_machine = descriptor
+		.targets
+		.iter()
+		.any(|t| t.architecture == "x86_64" && t.machines.iter().any(|m| m.starts_with(machine)));
+
+	// confidential computing firmware only boots encrypted guests
+	let confidential = descriptor
+		.features
+		.iter()
+		.any(|f| f.starts_with("amd-sev") || f == "intel-tdx");
+
+	descriptor.interface_types.iter().any(|t| t == "uefi")
+		&& descriptor.mapping.device == "flash"
+		&& descriptor.mapping.executable.is_some()
+		&& supports_machine
+		&& !confidential
+		&& has("secure-boot") == wanted.secure_boot
+		&& (!wanted.enrolled_keys || has("enrolled-keys"))
+		&& (wanted.secure_boot || !has("requires-smm"))
+}
+
+fn score(descriptor: &Descriptor, wanted: OvmfFeatures) -> u8 {
+	let enrolled_keys = descriptor.features.iter().any(|f| f == "enrolled-keys");
+
+	// there is no feature for the flash size, but the files are named after it
+	let is_4m = descriptor
+		.mapping
+		.executable
+		.as_ref()
+		.is_some_and(|e| e.filename.to_string_lossy().contains("4M"));
+
+	u8::from(enrolled_keys == wanted.enrolled_keys) + u8::from(is_4m == wanted.size_4m)
+}
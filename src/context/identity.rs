@@ -0,0 +1,136 @@
+use super::dmi::{self, Structure};
+use super::rng::Rng;
+use super::SmBiosMap;
+
+// Values vendors leave in place of a real one, randomizing them would stand out more than keeping them
+const PLACEHOLDERS: [&str; 9] = [
+	"default string",
+	"to be filled by o.e.m.",
+	"not specified",
+	"not applicable",
+	"unknown",
+	"none",
+	"n/a",
+	"system serial number",
+	"chassis serial number",
+];
+
+const DIGITS: &[u8] = b"0123456789";
+const UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPER_HEX: &[u8] = b"0123456789ABCDEF";
+const LOWER_HEX: &[u8] = b"0123456789abcdef";
+
+// Offsets of the serial number and asset tag string fields
+const SERIAL_FIELDS: [(u8, &[usize]); 5] = [
+	(dmi::SYSTEM_INFORMATION, &[0x07]),
+	(2, &[0x07, 0x08]),
+	(3, &[0x07, 0x08]),
+	(dmi::PROCESSOR, &[0x20, 0x21]),
+	(dmi::MEMORY_DEVICE, &[0x18, 0x19]),
+];
+
+/// Replaces serial numbers, asset tags and the system UUID with values derived from `seed`.  
+/// Each value keeps the format of the one it replaces, so it still looks like it came from the same vendor.
+pub fn randomize(map: &mut SmBiosMap, structures: &mut [Structure], seed: u64) {
+	let uuid = generate_uuid(&mut Rng::new(&format!("{seed:016x}/uuid")));
+
+	for (smbios_type, fields) in map.iter_mut() {
+		for (key, value) in fields.iter_mut() {
+			match key.as_str() {
+				"uuid" => value.clone_from(&uuid),
+				"serial" | "asset" => {
+					let mut rng = Rng::new(&format!("{seed:016x}/{}/{key}", *smbios_type as u8));
+					*value = randomize_like(value, &mut rng);
+				}
+				_ => {}
+			}
+		}
+	}
+
+	for (index, structure) in structures.iter_mut().enumerate() {
+		if structure.kind == dmi::SYSTEM_INFORMATION {
+			dmi::set_uuid(structure, &uuid);
+		}
+
+		let offsets = SERIAL_FIELDS
+			.iter()
+			.find(|(kind, _)| *kind == structure.kind)
+			.map(|(_, o)| *o);
+
+		for &offset in offsets.unwrap_or_default() {
+			let Some(value) = structure.string(offset) else {
+				continue;
+			};
+
+			// keyed by position, so every DIMM gets its own serial number
+			let mut rng = Rng::new(&format!("{seed:016x}/{index}/{offset}"));
+			let randomized = randomize_like(value, &mut rng);
+
+			if randomized != value {
+				structure.set_string(offset, &randomized);
+			}
+		}
+	}
+}
+
+/// Generates a value with the same shape as `template`, digits stay digits, letters stay letters of the same case.  
+/// Letters before the first digit often identify the vendor or factory, so they are kept.
+fn randomize_like(template: &str, rng: &mut Rng) -> String {
+	let trimmed = template.trim();
+
+	if trimmed.is_empty()
+		|| PLACEHOLDERS.contains(&trimmed.to_lowercase().as_str())
+		|| trimmed.chars().all(|c| c == '0' || !c.is_ascii_alphanumeric())
+	{
+		return template.to_string();
+	}
+
+	let (prefix, rest) = template.split_at(template.find(|c: char| c.is_ascii_digit()).unwrap_or(0));
+
+	// e.g. memory serial numbers, digits in them may be replaced by letters but only of the case already used
+	let hex = rest
+		.chars()
+		.all(|c| c.is_ascii_hexdigit() || !c.is_ascii_alphanumeric());
+	let hex_digits = match (
+		rest.contains(|c: char| c.is_ascii_uppercase()),
+		rest.contains(|c: char| c.is_ascii_lowercase()),
+	) {
+		(true, _) => UPPER_HEX,
+		(false, true) => LOWER_HEX,
+		(false, false) => DIGITS,
+	};
+
+	let randomized = rest.chars().map(|c| {
+		let set = match c {
+			'0'..='9' if hex => hex_digits,
+			'0'..='9' => DIGITS,
+			'A'..='F' if hex => UPPER_HEX,
+			'a'..='f' if hex => LOWER_HEX,
+			'A'..='Z' => UPPER,
+			'a'..='z' => LOWER,
+			_ => return c,
+		};
+
+		char::from(rng.pick(set))
+	});
+
+	prefix.chars().chain(randomized).collect()
+}
+
+// Version 4 layout, like the UUIDs firmware vendors generate
+fn generate_uuid(rng: &mut Rng) -> String {
+	let mut bytes = [rng.next_u64().to_be_bytes(), rng.next_u64().to_be_bytes()].concat();
+	bytes[6] = (bytes[6] & 0x0f) | 0x40;
+	bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+	let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+	format!(
+		"{}-{}-{}-{}-{}",
+		&hex[..8],
+		&hex[8..12],
+		&hex[12..16],
+		&hex[16..20],
+		&hex[20..]
+	)
+}
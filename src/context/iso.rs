@@ -0,0 +1,178 @@
+// Minimal ISO9660 writer with Joliet extensions, enough for a single file in the root directory
+
+const SECTOR: usize = 2048;
+
+// Fixed layout: system area, descriptors, path tables and root directories, then the file
+const PRIMARY_DESCRIPTOR: usize = 16;
+const JOLIET_DESCRIPTOR: usize = 17;
+const TERMINATOR: usize = 18;
+const PRIMARY_PATH_TABLE_L: usize = 19;
+const PRIMARY_PATH_TABLE_M: usize = 20;
+const JOLIET_PATH_TABLE_L: usize = 21;
+const JOLIET_PATH_TABLE_M: usize = 22;
+const PRIMARY_ROOT: usize = 23;
+const JOLIET_ROOT: usize = 24;
+const FILE_DATA: usize = 25;
+
+const PATH_TABLE_SIZE: u32 = 10;
+
+/// Builds an image with `contents` stored as `name` in the root directory.
+/// Plain ISO9660 names are uppercased and may be at most 31 characters long, Joliet preserves them.
+pub fn build(volume_id: &str, name: &str, contents: &[u8]) -> Vec<u8> {
+	let file_sectors = contents.len().div_ceil(SECTOR);
+	let mut image = vec![0; (FILE_DATA + file_sectors) * SECTOR];
+
+	let primary_name = format!("{};1", name.to_ascii_uppercase());
+	let joliet_name = ucs2(name);
+	let file = File {
+		extent: FILE_DATA as u32,
+		size: contents.len() as u32,
+	};
+
+	write_descriptor(&mut image, PRIMARY_DESCRIPTOR, volume_id, false);
+	write_descriptor(&mut image, JOLIET_DESCRIPTOR, volume_id, true);
+	write_terminator(&mut image);
+
+	write_path_table(&mut image, PRIMARY_PATH_TABLE_L, PRIMARY_ROOT, false);
+	write_path_table(&mut image, PRIMARY_PATH_TABLE_M, PRIMARY_ROOT, true);
+	write_path_table(&mut image, JOLIET_PATH_TABLE_L, JOLIET_ROOT, false);
+	write_path_table(&mut image, JOLIET_PATH_TABLE_M, JOLIET_ROOT, true);
+
+	write_root(&mut image, PRIMARY_ROOT, primary_name.as_bytes(), &file);
+	write_root(&mut image, JOLIET_ROOT, &joliet_name, &file);
+
+	image[FILE_DATA * SECTOR..][..contents.len()].copy_from_slice(contents);
+	image
+}
+
+struct File {
+	extent: u32,
+	size: u32,
+}
+
+fn write_descriptor(image: &mut [u8], sector: usize, volume_id: &str, joliet: bool) {
+	let total_sectors = (image.len() / SECTOR) as u32;
+	let (path_table_l, path_table_m, root) = match joliet {
+		false => (PRIMARY_PATH_TABLE_L, PRIMARY_PATH_TABLE_M, PRIMARY_ROOT),
+		true => (JOLIET_PATH_TABLE_L, JOLIET_PATH_TABLE_M, JOLIET_ROOT),
+	};
+
+	let d = &mut image[sector * SECTOR..][..SECTOR];
+	d[0] = if joliet { 2 } else { 1 };
+	d[1..6].copy_from_slice(b"CD001");
+	d[6] = 1;
+
+	// system, volume and the various identifiers are padded with spaces
+	fill_text(&mut d[8..40], "", joliet);
+	fill_text(&mut d[40..72], &volume_id.to_ascii_uppercase(), joliet);
+	d[80..88].copy_from_slice(&both_u32(total_sectors));
+
+	if joliet {
+		// UCS-2 level 3
+		d[88..91].copy_from_slice(b"%/E");
+	}
+
+	d[120..124].copy_from_slice(&both_u16(1));
+	d[124..128].copy_from_slice(&both_u16(1));
+	d[128..132].copy_from_slice(&both_u16(SECTOR as u16));
+	d[132..140].copy_from_slice(&both_u32(PATH_TABLE_SIZE));
+	d[140..144].copy_from_slice(&(path_table_l as u32).to_le_bytes());
+	d[148..152].copy_from_slice(&(path_table_m as u32).to_be_bytes());
+	d[156..190].copy_from_slice(&directory_record(root as u32, SECTOR as u32, &[0], true));
+
+	for range in [190..318, 318..446, 446..574, 574..702, 702..739, 739..776, 776..813] {
+		fill_text(&mut d[range], "", joliet);
+	}
+
+	// creation, modification, expiration and effective dates are left unspecified
+	for start in [813, 830, 847, 864] {
+		d[start..start + 16].fill(b'0');
+	}
+
+	d[881] = 1;
+}
+
+fn write_terminator(image: &mut [u8]) {
+	let d = &mut image[TERMINATOR * SECTOR..][..SECTOR];
+	d[0] = 255;
+	d[1..6].copy_from_slice(b"CD001");
+	d[6] = 1;
+}
+
+fn write_path_table(image: &mut [u8], sector: usize, root: usize, big_endian: bool) {
+	let t = &mut image[sector * SECTOR..][..PATH_TABLE_SIZE as usize];
+
+	// the root is the only entry, its name is a single zero byte and it is its own parent
+	t[0] = 1;
+
+	match big_endian {
+		false => {
+			t[2..6].copy_from_slice(&(root as u32).to_le_bytes());
+			t[6..8].copy_from_slice(&1u16.to_le_bytes());
+		}
+		true => {
+			t[2..6].copy_from_slice(&(root as u32).to_be_bytes());
+			t[6..8].copy_from_slice(&1u16.to_be_bytes());
+		}
+	}
+}
+
+fn write_root(image: &mut [u8], sector: usize, name: &[u8], file: &File) {
+	let records = [
+		directory_record(sector as u32, SECTOR as u32, &[0], true),
+		directory_record(sector as u32, SECTOR as u32, &[1], true),
+		directory_record(file.extent, file.size, name, false),
+	]
+	.concat();
+
+	image[sector * SECTOR..][..records.len()].copy_from_slice(&records);
+}
+
+fn directory_record(extent: u32, size: u32, name: &[u8], directory: bool) -> Vec<u8> {
+	let length = 33 + name.len() + (name.len() + 1) % 2;
+	let mut record = vec![0; length];
+
+	record[0] = length as u8;
+	record[2..10].copy_from_slice(&both_u32(extent));
+	record[10..18].copy_from_slice(&both_u32(size));
+	record[25] = if directory { 2 } else { 0 };
+	record[28..32].copy_from_slice(&both_u16(1));
+	record[32] = name.len() as u8;
+	record[33..33 + name.len()].copy_from_slice(name);
+
+	record
+}
+
+fn fill_text(field: &mut [u8], text: &str, joliet: bool) {
+	let text = match joliet {
+		true => ucs2(text),
+		false => text.as_bytes().to_vec(),
+	};
+
+	// UCS-2 spaces are 0x0020, odd length fields end with a stray zero byte
+	for (index, byte) in field.iter_mut().enumerate() {
+		*byte = match joliet && index % 2 == 0 {
+			true => 0,
+			false => b' ',
+		};
+	}
+
+	let length = text.len().min(field.len());
+	field[..length].copy_from_slice(&text[..length]);
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+	text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+	let [l0, l1] = value.to_le_bytes();
+	let [b0, b1] = value.to_be_bytes();
+	[l0, l1, b0, b1]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+	let [l0, l1, l2, l3] = value.to_le_bytes();
+	let [b0, b1, b2, b3] = value.to_be_bytes();
+	[l0, l1, l2, l3, b0, b1, b2, b3]
+}
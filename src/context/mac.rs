@@ -0,0 +1,56 @@
+use super::rng::Rng;
+use super::MacAddress;
+use std::fs;
+
+/// Turns the configured mode into an address, `None` leaves it up to QEMU.
+pub fn resolve(mode: &MacAddress, profile: &str, index: usize) -> Option<String> {
+	match mode {
+		MacAddress::Default => None,
+		MacAddress::Fixed(mac) => Some(mac.clone()),
+		MacAddress::Profile => Some(format_mac(derive(profile, index))),
+		MacAddress::HostOui(interface) => Some(format_mac(from_host_oui(interface, profile, index))),
+	}
+}
+
+// Locally administered unicast, so it can't collide with any vendor's address
+fn derive(profile: &str, index: usize) -> [u8; 6] {
+	let hash = Rng::hash(&format!("{profile}/{index}")).to_be_bytes();
+
+	let mut mac = [0; 6];
+	mac.copy_from_slice(&hash[..6]);
+	mac[0] = (mac[0] & 0xfc) | 0x02;
+
+	mac
+}
+
+fn from_host_oui(interface: &str, profile: &str, index: usize) -> [u8; 6] {
+	let Some(host) = read_host_mac(interface) else {
+		log::warn!("unable to read mac address of {interface}, deriving it from the profile name instead");
+		return derive(profile, index);
+	};
+
+	let mut mac = derive(profile, index);
+	mac[..3].copy_from_slice(&host[..3]);
+
+	// two identical addresses on the same network wreak havoc
+	if mac == host {
+		mac[5] ^= 0x01;
+	}
+
+	mac
+}
+
+fn read_host_mac(interface: &str) -> Option<[u8; 6]> {
+	let address = fs::read_to_string(format!("/sys/class/net/{interface}/address")).ok()?;
+	let bytes = address
+		.trim()
+		.split(':')
+		.map(|b| u8::from_str_radix(b, 16).ok())
+		.collect::<Option<Vec<_>>>()?;
+
+	bytes.try_into().ok()
+}
+
+fn format_mac(mac: [u8; 6]) -> String {
+	mac.map(|b| format!("{b:02x}")).join(":")
+}
@@ -1,13 +1,27 @@
 use nix::sys::stat::Mode;
 use nix::unistd::{Gid, Uid};
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
 
 mod build;
 mod builder;
+mod cpu;
+mod disk;
+pub mod dmi;
+mod firmware;
+mod identity;
+mod iso;
+mod mac;
+mod numa;
+mod rng;
 mod smbios;
+mod topology;
+mod unattend;
 mod util;
 
 pub use builder::ContextBuilder;
+pub use disk::external_snapshot;
 
 #[derive(Clone, Debug)]
 pub enum UsbDevice {
@@ -15,12 +29,146 @@ pub enum UsbDevice {
 	Device(String),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+	pub address: String,
+	pub romfile: Option<PathBuf>,
+	pub reset_method: Option<String>,
+	pub reset: Option<PciReset>,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum PciReset {
+	/// Resets just the device, using the methods in its `reset_method`.
+	Function,
+	/// Resets the whole bus the device sits on. Affects all other devices on that bus.
+	Bus,
+}
+
+/// Typed alternative to a raw `-cpu` string, see [`ContextBuilder::cpu_options`].
+#[derive(Clone, Debug)]
+pub struct CpuOptions {
+	/// CPU model, `host` passes the host's CPU through. See `qemu-system-x86_64 -cpu help`.
+	pub model: String,
+	/// CPUID vendor string, e.g. `AuthenticAMD`. Must be exactly 12 characters.
+	pub vendor: Option<String>,
+	/// Hides the KVM signature from the guest, which some drivers and anticheats look for.
+	pub hide_kvm: bool,
+	/// Hyper-V enlightenments, these speed up Windows guests considerably.
+	pub hyperv: Vec<HyperV>,
+	/// Hypervisor vendor reported through the Hyper-V CPUID leaves, at most 12 characters.
+	pub hv_vendor_id: Option<String>,
+	/// CPU features to enable, e.g. `topoext` on AMD.
+	pub enable: Vec<String>,
+	/// CPU features to disable, e.g. `svm`.
+	pub disable: Vec<String>,
+}
+
+impl Default for CpuOptions {
+	fn default() -> Self {
+		Self {
+			model: String::from("host"),
+			vendor: None,
+			hide_kvm: false,
+			hyperv: vec![],
+			hv_vendor_id: None,
+			enable: vec![],
+			disable: vec![],
+		}
+	}
+}
+
+// Not all configs use all presets
+#[allow(unused)]
+impl CpuOptions {
+	/// Host CPU with KVM hidden and the enlightenments Windows benefits from.
+	/// Add `topoext` to `enable` on AMD hosts, so the guest sees SMT.
+	pub fn windows_gaming() -> Self {
+		Self {
+			hide_kvm: true,
+			hyperv: vec![
+				HyperV::Relaxed,
+				HyperV::Vapic,
+				HyperV::Spinlocks(0x1fff),
+				HyperV::Vpindex,
+				HyperV::Runtime,
+				HyperV::Synic,
+				HyperV::Stimer,
+				HyperV::Reset,
+				HyperV::Frequencies,
+				HyperV::Time,
+				HyperV::TlbFlush,
+				HyperV::Ipi,
+			],
+			hv_vendor_id: Some(String::from("thisisnotavm")),
+			..Default::default()
+		}
+	}
+}
+
+/// Hyper-V enlightenments, see [QEMU's documentation](https://www.qemu.org/docs/master/system/i386/hyperv.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(unused)]
+pub enum HyperV {
+	Relaxed,
+	Vapic,
+	/// Retry count before a spinlock is reported to the hypervisor, at least `0xfff`.
+	Spinlocks(u32),
+	Vpindex,
+	Runtime,
+	/// Requires [`HyperV::Vpindex`].
+	Synic,
+	/// Requires [`HyperV::Synic`] and [`HyperV::Time`].
+	Stimer,
+	/// Requires [`HyperV::Stimer`].
+	StimerDirect,
+	Reset,
+	Frequencies,
+	Time,
+	Reenlightenment,
+	/// Requires [`HyperV::Vpindex`].
+	TlbFlush,
+	/// Requires [`HyperV::Vpindex`].
+	Ipi,
+	/// Nested virtualization on Intel hosts. Requires [`HyperV::Vapic`].
+	Evmcs,
+	Avic,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum Numa {
+	/// Binds guest RAM to the host nodes of the pinned CPUs and passed through PCI devices.
+	Bind,
+	/// Mirrors the nodes of the pinned vCPUs in the guest, binding each guest node's RAM to its host node.
+	/// Unlike [`Numa::Bind`], nodes of passed through PCI devices without pinned vCPUs get no RAM.
+	/// Requires [`ContextBuilder::smp_auto`] or [`ContextBuilder::smp_from_affinity`].
+	Mirror,
+}
+
 pub struct TmpFile {
 	pub path: PathBuf,
 	pub uid: Uid,
 	pub gid: Gid,
 	pub mode: Mode,
+	pub contents: Vec<u8>,
+	/// holds secrets, deleted again once the VM has stopped
+	pub private: bool,
+}
+
+// contents may be entire disk images, don't dump them into the log
+impl Debug for TmpFile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TmpFile")
+			.field("path", &self.path)
+			.field("uid", &self.uid)
+			.field("gid", &self.gid)
+			.field("mode", &self.mode)
+			.field("contents", &format_args!("{} bytes", self.contents.len()))
+			.field("private", &self.private)
+			.finish()
+	}
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -45,12 +193,215 @@ pub enum Window {
 pub enum BiosType {
 	Default,
 	Ovmf(PathBuf),
+	/// Separate code and variable store, keeping UEFI variables across runs
+	OvmfPflash {
+		code: PathBuf,
+		vars_template: PathBuf,
+		secure_boot: bool,
+	},
+}
+
+impl BiosType {
+	pub fn is_uefi(&self) -> bool {
+		!matches!(self, Self::Default)
+	}
+}
+
+/// Firmware features [`ContextBuilder::ovmf_auto`] looks for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OvmfFeatures {
+	/// Implies the q35 machine type with SMM
+	pub secure_boot: bool,
+	/// Microsoft's keys are enrolled in the variable store, so Secure Boot is enabled right away
+	pub enrolled_keys: bool,
+	/// Prefer the 4MB builds, required for larger variable stores e.g. with many Secure Boot certificates
+	pub size_4m: bool,
+}
+
+/// Writable per-profile copy of the OVMF variable store.
+#[derive(Clone, Debug)]
+pub struct Nvram {
+	pub path: PathBuf,
+	pub template: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct Disk {
+	pub path: PathBuf,
+	pub format: DiskFormat,
+	pub options: DiskOptions,
+	/// qcow2 overlay receiving all writes, recreated on every run
+	pub overlay: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskFormat {
+	Raw,
+	Qcow2,
+	Vmdk,
+}
+
+impl DiskFormat {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Raw => "raw",
+			Self::Qcow2 => "qcow2",
+			Self::Vmdk => "vmdk",
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiskOptions {
+	pub bus: DiskBus,
+	/// Image format, detected from the header when unset. Physical disks are opened as raw unless set.
+	pub format: Option<DiskFormat>,
+	/// Host caching mode, QEMU defaults to writeback.
+	pub cache: Option<DiskCache>,
+	/// Asynchronous IO backend, QEMU defaults to threads.
+	pub aio: Option<DiskAio>,
+	/// Passes TRIM/UNMAP requests from the guest through to the disk.
+	pub discard: bool,
+	/// Detects writes of all zeroes and turns them into (unmapping, with `discard`) zero writes.
+	pub detect_zeroes: bool,
+	/// Reported rotation rate in RPM, `1` marks the disk as an SSD. Not supported on VirtIO block and NVMe.
+	pub rotation_rate: Option<u16>,
+	/// Processes IO in a dedicated thread. Only supported on VirtIO block and VirtIO SCSI.
+	pub iothread: bool,
+	/// Reported physical block size in bytes, e.g. `4096` for advanced format disks.
+	pub physical_block_size: Option<u32>,
+	pub identity: DiskIdentity,
+}
+
+/// What the guest sees instead of "QEMU HARDDISK". Unset fields keep QEMU's defaults.
+#[derive(Clone, Debug, Default)]
+pub struct DiskIdentity {
+	pub serial: Option<String>,
+	/// World Wide Name, e.g. `0x5002538e40a1b2c3`. Not supported on VirtIO block and NVMe.
+	pub wwn: Option<u64>,
+	/// Only supported on VirtIO SCSI, IDE and AHCI report it as part of the model.
+	pub vendor: Option<String>,
+	/// Model name. Not supported on VirtIO block and NVMe.
+	pub product: Option<String>,
+}
+
+// Not all configs use all presets
+#[allow(unused)]
+impl DiskOptions {
+	/// Sensible options for SSDs: VirtIO SCSI with an iothread, no host cache and TRIM.
+	/// Keeps Windows from defragmenting the disk. Requires driver installation on guest.
+	pub fn ssd() -> Self {
+		Self {
+			bus: DiskBus::VirtioScsi,
+			format: None,
+			cache: Some(DiskCache::None),
+			aio: Some(DiskAio::Native),
+			discard: true,
+			detect_zeroes: true,
+			rotation_rate: Some(1),
+			iothread: true,
+			physical_block_size: None,
+			identity: DiskIdentity::default(),
+		}
+	}
+}
+
+impl From<DiskBus> for DiskOptions {
+	fn from(bus: DiskBus) -> Self {
+		Self {
+			bus,
+			..Default::default()
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(unused)]
+pub enum DiskBus {
+	/// Emulated IDE disk. Compatible with Windows out-of-the box, but slow.
+	Ide,
+	/// Emulated SATA disk on an AHCI controller. Compatible with Windows out-of-the box.
+	Ahci,
+	/// VirtIO block device. Fast, but requires driver installation on guest.
+	#[default]
+	VirtioBlk,
+	/// VirtIO SCSI disk. Fast, supports TRIM and rotation rate, but requires driver installation on guest.
+	VirtioScsi,
+	/// Emulated NVMe drive. Compatible with Windows out-of-the box.
+	Nvme,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum DiskCache {
+	/// Bypasses the host page cache. Best for physical disks.
+	None,
+	Writeback,
+	Writethrough,
+	DirectSync,
+	/// Ignores flushes from the guest. Fast, but loses data when the host crashes.
+	Unsafe,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum DiskAio {
+	Threads,
+	/// Linux native AIO. Requires a cache mode bypassing the host page cache.
+	Native,
+	IoUring,
 }
 
 #[derive(Clone, Debug)]
-pub enum Disk {
-	Raw(PathBuf),
-	Virtio(PathBuf),
+pub struct Overlay {
+	pub path: PathBuf,
+	pub backing_file: PathBuf,
+	pub backing_format: DiskFormat,
+	/// delete the overlay after QEMU exits
+	pub discard: bool,
+}
+
+/// Answers for an unattended Windows installation, see [`ContextBuilder::unattend`].
+#[derive(Clone, Debug)]
+pub struct Unattend {
+	/// Language and regional format, e.g. `en-US`
+	pub locale: String,
+	/// Keyboard layout, e.g. `en-US` or `de-DE`
+	pub input_locale: String,
+	/// Windows time zone name, e.g. `W. Europe Standard Time`
+	pub timezone: String,
+	pub user: String,
+	pub password: String,
+	/// Without a key, setup asks for one. Microsoft's generic installation keys select an edition without activating it.
+	pub product_key: Option<String>,
+	/// Guest disk to install to, starting at 0. **It gets wiped.**
+	pub disk: u32,
+	/// Folder of the drivers on the virtio-win ISO, e.g. `w11` or `w10`
+	pub driver_version: String,
+}
+
+impl Default for Unattend {
+	fn default() -> Self {
+		Self {
+			locale: String::from("en-US"),
+			input_locale: String::from("en-US"),
+			timezone: String::from("UTC"),
+			user: String::from("user"),
+			password: String::new(),
+			product_key: None,
+			disk: 0,
+			driver_version: String::from("w11"),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Ephemeral {
+	No,
+	/// Overlays are deleted after QEMU exits
+	Discard,
+	/// Overlays are left in place for inspection
+	Keep,
 }
 
 #[derive(Clone, Debug)]
@@ -79,11 +430,178 @@ pub enum IntelHdaType {
 	Micro,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
+pub struct Nic {
+	pub networking: Networking,
+	pub mac: MacAddress,
+}
+
+#[derive(Clone, Debug)]
 pub enum Networking {
-	None,
 	User,
 	VirtioUser,
+	/// TAP device attached to an existing bridge
+	Bridge {
+		bridge: String,
+		model: NicModel,
+	},
+	/// macvtap device on top of a host interface, in bridge mode
+	Macvtap {
+		parent: String,
+		model: NicModel,
+	},
+	/// user-mode networking through a passt helper process
+	Passt {
+		model: NicModel,
+	},
+}
+
+impl Networking {
+	/// The guest sits behind NAT and needs port forwards to be reachable.
+	pub fn is_user_mode(&self) -> bool {
+		matches!(self, Self::User | Self::VirtioUser | Self::Passt { .. })
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum NicModel {
+	/// Intel 82540EM. Compatible with Windows out-of-the box.
+	E1000,
+	/// Intel 82574L. Compatible with Windows out-of-the box.
+	E1000e,
+	/// Realtek RTL8139. Compatible with almost anything, but slow.
+	Rtl8139,
+	/// VirtIO network device. Fastest, but requires driver installation on guest.
+	VirtioNetPci,
+}
+
+impl NicModel {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::E1000 => "e1000",
+			Self::E1000e => "e1000e",
+			Self::Rtl8139 => "rtl8139",
+			Self::VirtioNetPci => "virtio-net-pci",
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+#[allow(unused)]
+pub enum MacAddress {
+	/// QEMU's default, `52:54:00:12:34:56` counting up. The OUI is well known to belong to QEMU.
+	Default,
+	/// Specified address, e.g. `00:d8:61:12:34:56`
+	Fixed(String),
+	/// Locally administered address derived from the profile name, stable across runs.
+	/// Only the profile name goes in, so other hosts using the same name end up with the same address.
+	Profile,
+	/// Vendor part (OUI) copied from the host interface, the rest derived from the profile name like [`MacAddress::Profile`]
+	HostOui(String),
+}
+
+/// Options only applicable to user networking.
+#[derive(Clone, Debug, Default)]
+pub struct UserNetOptions {
+	pub forwards: Vec<PortForward>,
+	/// Directory shared through QEMU's built-in SMB server, requires samba on the host
+	pub smb: Option<PathBuf>,
+	/// Guest subnet in CIDR notation, e.g. `10.0.2.0/24`
+	pub net: Option<String>,
+	/// Address of the virtual DNS server, has to be within the guest subnet
+	pub dns: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PortForward {
+	pub protocol: Protocol,
+	pub host_port: u16,
+	pub guest_port: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+}
+
+impl Protocol {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Tcp => "tcp",
+			Self::Udp => "udp",
+		}
+	}
+}
+
+/// Host network interface created before and removed after running the VM.
+#[derive(Clone, Debug)]
+pub enum NetDevice {
+	Tap {
+		name: String,
+		bridge: String,
+	},
+	Macvtap {
+		name: String,
+		parent: String,
+		mac: String,
+		/// file descriptor number QEMU expects the opened device at
+		fd: i32,
+	},
+	/// Not an interface, but a helper process QEMU connects to
+	Passt {
+		socket: PathBuf,
+		forwards: Vec<PortForward>,
+	},
+}
+
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub enum Tpm {
+	No,
+	/// TPM Interface Specification device. Works with both BIOS and UEFI.
+	Tis,
+	/// Command Response Buffer device. Requires UEFI, Windows 11 prefers it.
+	Crb,
+}
+
+/// Directory on the host shared with the guest through virtiofs.
+#[derive(Clone, Debug)]
+pub struct SharedFolder {
+	pub path: PathBuf,
+	/// name the guest mounts the folder by
+	pub tag: String,
+}
+
+/// Helper process started before and stopped after running the VM.
+#[derive(Clone, Debug)]
+pub enum Daemon {
+	Virtiofsd {
+		socket: PathBuf,
+		path: PathBuf,
+	},
+	/// `state` persists across runs
+	Swtpm {
+		socket: PathBuf,
+		state: PathBuf,
+	},
+}
+
+impl Daemon {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Virtiofsd { .. } => "virtiofsd",
+			Self::Swtpm { .. } => "swtpm",
+		}
+	}
+
+	pub fn socket(&self) -> &Path {
+		match self {
+			Self::Virtiofsd { socket, .. } | Self::Swtpm { socket, .. } => socket,
+		}
+	}
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -111,7 +629,10 @@ pub enum SmBiosType {
 	BaseboardInformation = 2,
 	EnclosureInformation = 3,
 	ProcessorInformation = 4,
+	PortConnector = 8,
+	SystemSlot = 9,
 	OemStrings = 11,
+	PhysicalMemoryArray = 16,
 	MemoryDevice = 17,
 }
 
@@ -121,10 +642,23 @@ pub type SmBiosMap = HashMap<SmBiosType, HashMap<String, String>>;
 pub struct Context {
 	pub env: HashMap<String, String>,
 	pub args: Vec<String>,
-	pub pci: Vec<String>,
+	pub pci: Vec<PciDevice>,
 	pub pat_dealloc: Vec<String>,
 	pub unload_drivers: Option<Vec<String>>,
+	pub release_framebuffer: bool,
+	pub display_manager: Option<String>,
+	pub terminate_gpu_holders: bool,
 	pub tmp_files: Vec<TmpFile>,
+	pub overlays: Vec<Overlay>,
+	/// qcow2 images eligible for snapshots
+	pub images: Vec<PathBuf>,
+	pub net_devices: Vec<NetDevice>,
+	pub daemons: Vec<Daemon>,
+	pub nvram: Option<Nvram>,
+	/// host ports QEMU will bind for user networking
+	pub forwards: Vec<PortForward>,
 	pub cpu_affinity: Option<String>,
 	pub cpu_governor: Option<String>,
+	/// host CPU for each vCPU, empty when not pinned individually
+	pub vcpu_pins: Vec<usize>,
 }
@@ -0,0 +1,153 @@
+use super::topology::parse_cpu_list;
+use super::{smbios, Numa, PciDevice};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+const PCI_DIR: &str = "/sys/bus/pci/devices";
+
+/// Host nodes guest RAM is bound to, optionally split into guest nodes.
+#[derive(Debug)]
+pub struct Placement {
+	pub host_nodes: BTreeSet<u32>,
+	/// empty unless the topology is mirrored in the guest
+	pub guest_nodes: Vec<GuestNode>,
+}
+
+#[derive(Debug)]
+pub struct GuestNode {
+	pub host_node: u32,
+	pub vcpus: Vec<usize>,
+	pub megabytes: u64,
+}
+
+/// Finds the host nodes of the pinned CPUs and of the passed through devices.
+/// Returns `None` when there is nothing to bind, e.g. on hosts with a single node.
+pub fn place(
+	numa: Numa,
+	affinity: Option<&str>,
+	pins: &[usize],
+	pci: &[PciDevice],
+	ram: &str,
+) -> io::Result<Option<Placement>> {
+	let nodes = read_nodes()?;
+
+	if nodes.len() < 2 {
+		log::info!("host has a single numa node, not binding guest memory");
+		return Ok(None);
+	}
+
+	let cpus = match affinity {
+		Some(affinity) => {
+			parse_cpu_list(affinity).ok_or_else(|| io::Error::other(format!("invalid cpu list {affinity}")))?
+		}
+		None => BTreeSet::new(),
+	};
+
+	let node_of = |cpu: &usize| nodes.iter().find(|(_, cpus)| cpus.contains(cpu)).map(|(id, _)| *id);
+	let cpu_nodes = cpus.iter().filter_map(node_of).collect::<BTreeSet<_>>();
+	let device_nodes = pci
+		.iter()
+		.filter_map(|d| device_node(&d.address))
+		.collect::<BTreeSet<_>>();
+
+	// memory is closest to the CPUs using it, but DMA from the GPU crosses nodes either way
+	if let Some(node) = device_nodes
+		.iter()
+		.find(|n| !cpu_nodes.is_empty() && !cpu_nodes.contains(n))
+	{
+		log::warn!("passed through devices sit on node {node}, but none of the pinned cpus do");
+
+		// guest nodes only exist where vCPUs are, so none of the guest's RAM ends up there
+		if matches!(numa, Numa::Mirror) && !pins.is_empty() {
+			log::warn!("mirrored numa nodes bind guest memory by cpu only, node {node} gets none");
+		}
+	}
+
+	let host_nodes = cpu_nodes.union(&device_nodes).copied().collect::<BTreeSet<_>>();
+
+	if host_nodes.is_empty() {
+		log::warn!("neither cpu_affinity nor the pci devices point to a numa node, not binding guest memory");
+		return Ok(None);
+	}
+
+	let guest_nodes = match numa {
+		Numa::Bind => vec![],
+		Numa::Mirror if pins.is_empty() => {
+			log::error!("mirroring numa nodes requires smp_auto or smp_from_affinity, only binding guest memory");
+			vec![]
+		}
+		Numa::Mirror => split(pins, ram, node_of)?,
+	};
+
+	Ok(Some(Placement {
+		host_nodes,
+		guest_nodes,
+	}))
+}
+
+// one guest node per host node with pinned vCPUs, RAM is split by vCPU count
+fn split(pins: &[usize], ram: &str, node_of: impl Fn(&usize) -> Option<u32>) -> io::Result<Vec<GuestNode>> {
+	let total =
+		smbios::parse_megabytes(ram).ok_or_else(|| io::Error::other(format!("unable to parse ram size {ram}")))?;
+	let mut guest_nodes: Vec<GuestNode> = vec![];
+
+	for (vcpu, cpu) in pins.iter().enumerate() {
+		let host_node = node_of(cpu).ok_or_else(|| io::Error::other(format!("cpu {cpu} is on no numa node")))?;
+
+		match guest_nodes.iter_mut().find(|n| n.host_node == host_node) {
+			Some(node) => node.vcpus.push(vcpu),
+			None => guest_nodes.push(GuestNode {
+				host_node,
+				vcpus: vec![vcpu],
+				megabytes: 0,
+			}),
+		}
+	}
+
+	let mut remaining = total;
+
+	for (index, node) in guest_nodes.iter_mut().enumerate().rev() {
+		node.megabytes = match index {
+			0 => remaining,
+			_ => total * node.vcpus.len() as u64 / pins.len() as u64,
+		};
+
+		remaining -= node.megabytes;
+	}
+
+	Ok(guest_nodes)
+}
+
+fn read_nodes() -> io::Result<Vec<(u32, BTreeSet<usize>)>> {
+	let mut nodes = vec![];
+
+	for entry in fs::read_dir(NODE_DIR)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		let Some(id) = name
+			.to_str()
+			.and_then(|n| n.strip_prefix("node"))
+			.and_then(|n| n.parse().ok())
+		else {
+			continue;
+		};
+
+		let cpulist = fs::read_to_string(entry.path().join("cpulist"))?;
+		let cpus = parse_cpu_list(cpulist.trim())
+			.ok_or_else(|| io::Error::other(format!("unable to parse cpus of node {id}")))?;
+
+		nodes.push((id, cpus));
+	}
+
+	nodes.sort_by_key(|(id, _)| *id);
+	Ok(nodes)
+}
+
+// -1 when the platform doesn't report it
+fn device_node(address: &str) -> Option<u32> {
+	let node = fs::read_to_string(Path::new(PCI_DIR).join(address).join("numa_node")).ok()?;
+	node.trim().parse().ok()
+}
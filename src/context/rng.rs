@@ -0,0 +1,68 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Deterministic SplitMix64 generator, the same key always yields the same sequence.
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(key: &str) -> Self {
+		Self(fnv1a(key))
+	}
+
+	/// A single value for `key` without advancing any state.
+	/// FNV-1a barely mixes a differing last byte, so the result goes through the finalizer as well.
+	pub fn hash(key: &str) -> u64 {
+		mix(fnv1a(key))
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		mix(self.0)
+	}
+
+	/// Picks one of `items`, which must not be empty.
+	pub fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+		items[(self.next_u64() % items.len() as u64) as usize]
+	}
+}
+
+/// Reads the seed stored at `path`, creating a random one if there is none yet.
+pub fn load_seed(path: &Path) -> io::Result<u64> {
+	if let Some(seed) = fs::read_to_string(path)
+		.ok()
+		.and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+	{
+		return Ok(seed);
+	}
+
+	let mut bytes = [0; 8];
+	File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes))?;
+
+	let seed = u64::from_ne_bytes(bytes);
+	let result = path
+		.parent()
+		.map_or(Ok(()), fs::create_dir_all)
+		.and_then(|()| fs::write(path, format!("{seed:016x}\n")));
+
+	// still usable, but won't be the same next time
+	if let Err(e) = result {
+		log::warn!("unable to store seed in {}: {e}", path.display());
+	}
+
+	Ok(seed)
+}
+
+// FNV-1a, stable across Rust versions unlike the std hashers
+fn fnv1a(key: &str) -> u64 {
+	key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+		(hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+	})
+}
+
+// SplitMix64 finalizer
+fn mix(z: u64) -> u64 {
+	let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	z ^ (z >> 31)
+}
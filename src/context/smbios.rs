@@ -1,6 +1,7 @@
 // long type names make it less readable
 #![allow(clippy::redundant_closure_for_method_calls)]
 
+use super::dmi::{self, Structure};
 use super::{SmBiosMap, SmBiosType};
 use smbioslib::*;
 use std::any::type_name;
@@ -19,7 +20,303 @@ pub fn populate_auto(map: &mut SmBiosMap) {
 	populate_enclosure_information(map);
 	populate_processor_information(map, get_table::<SMBiosProcessorInformation>(&dmi));
 	populate_oem_strings(map);
-	populate_memory_device(map, get_table::<SMBiosMemoryDevice>(&dmi));
+	populate_memory_device(map, get_populated_memory_device(&dmi));
+}
+
+// Handles QEMU uses for the types replaced here, its own type 19 structure refers to the memory array by it
+const PROCESSOR_HANDLE: u16 = 0x400;
+const PORT_CONNECTOR_HANDLE: u16 = 0x800;
+const SYSTEM_SLOT_HANDLE: u16 = 0x900;
+const MEMORY_ARRAY_HANDLE: u16 = 0x1000;
+const MEMORY_DEVICE_HANDLE: u16 = 0x1100;
+
+const NO_HANDLE: u16 = 0xffff;
+const NO_ERROR_INFORMATION: u16 = 0xfffe;
+
+#[derive(Clone, Copy)]
+enum Field {
+	Byte(usize),
+	Word(usize),
+	String(usize),
+}
+
+// Fields that can be set on the host's structures, named like QEMU's -smbios options where it has them.
+// The memory array's capacity and device count follow the DIMMs and can't be set
+const STRUCTURE_FIELDS: &[(SmBiosType, &str, &[Field])] = &[
+	(SmBiosType::PortConnector, "internal_reference", &[Field::String(0x04)]),
+	(SmBiosType::PortConnector, "external_reference", &[Field::String(0x06)]),
+	(
+		SmBiosType::PortConnector,
+		"connector_type",
+		&[Field::Byte(0x05), Field::Byte(0x07)],
+	),
+	(SmBiosType::PortConnector, "port_type", &[Field::Byte(0x08)]),
+	(SmBiosType::SystemSlot, "slot_designation", &[Field::String(0x04)]),
+	(SmBiosType::SystemSlot, "slot_type", &[Field::Byte(0x05)]),
+	(SmBiosType::SystemSlot, "slot_data_bus_width", &[Field::Byte(0x06)]),
+	(SmBiosType::SystemSlot, "current_usage", &[Field::Byte(0x07)]),
+	(SmBiosType::SystemSlot, "slot_length", &[Field::Byte(0x08)]),
+	(SmBiosType::SystemSlot, "slot_id", &[Field::Word(0x09)]),
+	(SmBiosType::SystemSlot, "slot_characteristics1", &[Field::Byte(0x0b)]),
+	(SmBiosType::SystemSlot, "slot_characteristics2", &[Field::Byte(0x0c)]),
+	(SmBiosType::PhysicalMemoryArray, "location", &[Field::Byte(0x04)]),
+	(SmBiosType::PhysicalMemoryArray, "use", &[Field::Byte(0x05)]),
+	(
+		SmBiosType::PhysicalMemoryArray,
+		"error_correction",
+		&[Field::Byte(0x06)],
+	),
+];
+
+/// Builds structures for every processor socket and DIMM, scaled to the guest, plus the host's port connectors and slots.
+/// QEMU generates only one kind of each, with most fields made up.
+pub fn populate_structures(ram: &str, smp: Option<&str>) -> Vec<Structure> {
+	let Ok(host) = dmi::read_host() else {
+		log::error!("unable to load dmi tables, cannot build smbios structures");
+		return vec![];
+	};
+
+	let mut structures = vec![];
+
+	match parse_megabytes(ram) {
+		Some(ram) => structures.extend(build_memory(&host, ram)),
+		None => log::warn!("unable to parse ram size {ram}, leaving memory devices to QEMU"),
+	}
+
+	structures.extend(build_processors(&host, Topology::parse(smp)));
+	structures.extend(renumber(&host, dmi::PORT_CONNECTOR, PORT_CONNECTOR_HANDLE));
+	structures.extend(renumber(&host, dmi::SYSTEM_SLOT, SYSTEM_SLOT_HANDLE));
+
+	structures
+}
+
+fn build_memory(host: &[Structure], ram: u64) -> Vec<Structure> {
+	let array = host.iter().find(|s| s.kind == dmi::PHYSICAL_MEMORY_ARRAY);
+
+	// size 0 means the slot is empty
+	let dimms = host
+		.iter()
+		.filter(|s| s.kind == dmi::MEMORY_DEVICE && s.word(0x0c).is_some_and(|size| size != 0))
+		.collect::<Vec<_>>();
+
+	let (Some(array), false) = (array, dimms.is_empty()) else {
+		log::warn!("no populated memory devices found, leaving them to QEMU");
+		return vec![];
+	};
+
+	let mut array = array.clone();
+	array.set_handle(MEMORY_ARRAY_HANDLE);
+	array.set_word(0x0b, NO_ERROR_INFORMATION);
+	array.set_word(0x0d, u16::try_from(dimms.len()).unwrap_or(u16::MAX));
+	set_memory_array_capacity(&mut array, ram);
+
+	let mut structures = vec![array];
+	let count = dimms.len() as u64;
+
+	for (index, dimm) in (0..).zip(dimms) {
+		// the guest's RAM is spread evenly, the first one takes the remainder
+		let size = ram / count + if index == 0 { ram % count } else { 0 };
+
+		let mut dimm = dimm.clone();
+		dimm.set_handle(MEMORY_DEVICE_HANDLE + index);
+		dimm.set_word(0x04, MEMORY_ARRAY_HANDLE);
+		dimm.set_word(0x06, NO_ERROR_INFORMATION);
+		set_memory_device_size(&mut dimm, size);
+
+		structures.push(dimm);
+	}
+
+	structures
+}
+
+/// Sets fields of port connectors, system slots and the memory array on every structure of that type.
+/// QEMU only takes fields for types it doesn't also get binary structures for, these are set on the binary instead.
+pub fn apply_fields(structures: &mut [Structure], map: &SmBiosMap) {
+	for (&smbios_type, fields) in map {
+		// without a structure of that type, QEMU takes the fields as usual
+		let kind = smbios_type as u8;
+
+		if !STRUCTURE_FIELDS.iter().any(|(t, ..)| *t == smbios_type) || !structures.iter().any(|s| s.kind == kind) {
+			continue;
+		}
+
+		for (name, value) in fields {
+			let Some((.., offsets)) = STRUCTURE_FIELDS.iter().find(|(t, n, _)| *t == smbios_type && n == name) else {
+				log::warn!("unknown field {name} of smbios type {kind}, ignoring it");
+				continue;
+			};
+
+			for structure in structures.iter_mut().filter(|s| s.kind == kind) {
+				for &offset in *offsets {
+					set_field(structure, offset, value);
+				}
+			}
+		}
+	}
+}
+
+fn set_field(structure: &mut Structure, field: Field, value: &str) {
+	// QEMU takes numbers in either base as well
+	let number = match value.strip_prefix("0x") {
+		Some(hex) => u16::from_str_radix(hex, 16).ok(),
+		None => value.parse().ok(),
+	};
+
+	match (field, number) {
+		(Field::String(offset), _) => structure.set_string(offset, value),
+		(Field::Word(offset), Some(number)) => structure.set_word(offset, number),
+		(Field::Byte(offset), Some(number)) => match u8::try_from(number) {
+			Ok(number) => structure.set_byte(offset, number),
+			Err(_) => log::warn!("smbios value {value} doesn't fit a byte, ignoring it"),
+		},
+		(_, None) => log::warn!("smbios value {value} is not a number, ignoring it"),
+	}
+}
+
+// the host's capacity may be less than the guest's RAM, which the DIMMs below would then exceed
+fn set_memory_array_capacity(array: &mut Structure, megabytes: u64) {
+	let kilobytes = megabytes.saturating_mul(1024);
+
+	match u32::try_from(kilobytes) {
+		Ok(capacity) if capacity < 0x8000_0000 => {
+			array.set_dword(0x07, capacity);
+			array.set_qword(0x0f, 0);
+		}
+		_ => {
+			array.set_dword(0x07, 0x8000_0000);
+			array.set_qword(0x0f, kilobytes.saturating_mul(1024));
+		}
+	}
+}
+
+// sizes of 32GB and up go into the extended size field
+fn set_memory_device_size(dimm: &mut Structure, megabytes: u64) {
+	match u16::try_from(megabytes) {
+		Ok(size) if size < 0x7fff => {
+			dimm.set_word(0x0c, size);
+			dimm.set_dword(0x1c, 0);
+		}
+		_ => {
+			dimm.set_word(0x0c, 0x7fff);
+			dimm.set_dword(0x1c, u32::try_from(megabytes).unwrap_or(u32::MAX));
+		}
+	}
+}
+
+fn build_processors(host: &[Structure], topology: Topology) -> Vec<Structure> {
+	// bit 6 of the status marks a populated socket
+	let Some(processor) = host
+		.iter()
+		.find(|s| s.kind == dmi::PROCESSOR && s.byte(0x18).is_some_and(|status| status & 0x40 != 0))
+	else {
+		log::warn!("no populated processor socket found, leaving processors to QEMU");
+		return vec![];
+	};
+
+	let threads = topology.cores.saturating_mul(topology.threads);
+
+	(0..topology.sockets)
+		.map(|index| {
+			let mut processor = processor.clone();
+			processor.set_handle(PROCESSOR_HANDLE + index);
+
+			// cache structures aren't passed along
+			for offset in [0x1a, 0x1c, 0x1e] {
+				processor.set_word(offset, NO_HANDLE);
+			}
+
+			// counts above 255 only fit the fields added in SMBIOS 3.0
+			for (offset, count) in [(0x23, topology.cores), (0x24, topology.cores), (0x25, threads)] {
+				processor.set_byte(offset, u8::try_from(count).unwrap_or(u8::MAX));
+			}
+
+			for (offset, count) in [
+				(0x2a, topology.cores),
+				(0x2c, topology.cores),
+				(0x2e, threads),
+				(0x30, threads),
+			] {
+				processor.set_word(offset, count);
+			}
+
+			processor
+		})
+		.collect()
+}
+
+fn renumber(host: &[Structure], kind: u8, base: u16) -> Vec<Structure> {
+	host.iter()
+		.filter(|s| s.kind == kind)
+		.zip(base..)
+		.map(|(structure, handle)| {
+			let mut structure = structure.clone();
+			structure.set_handle(handle);
+			structure
+		})
+		.collect()
+}
+
+/// Guest CPU topology, per socket.
+#[derive(Clone, Copy, Debug)]
+struct Topology {
+	sockets: u16,
+	/// per socket, across all of its dies
+	cores: u16,
+	threads: u16,
+}
+
+impl Topology {
+	/// Parses QEMU's `-smp` option, e.g. `sockets=1,dies=2,cores=6,threads=2` or just `12`.
+	fn parse(smp: Option<&str>) -> Self {
+		let mut cpus = None;
+		let mut topology = [None; 4];
+
+		for option in smp.unwrap_or_default().split(',') {
+			let (key, value) = option.split_once('=').unwrap_or(("cpus", option));
+			let value = value.parse::<u16>().ok();
+
+			match key {
+				"cpus" => cpus = value,
+				"sockets" => topology[0] = value,
+				"dies" => topology[1] = value,
+				"cores" => topology[2] = value,
+				"threads" => topology[3] = value,
+				_ => (),
+			}
+		}
+
+		let [sockets, dies, cores, threads] = topology;
+		let sockets = sockets.unwrap_or(1).max(1);
+		let dies = dies.unwrap_or(1).max(1);
+		let threads = threads.unwrap_or(1).max(1);
+
+		// QEMU puts all CPUs into cores when only the total is given, and counts cores per die
+		let cores = cores
+			.or_else(|| cpus.map(|c| c / sockets / dies / threads))
+			.unwrap_or(1)
+			.max(1);
+
+		Self {
+			sockets,
+			cores: cores.saturating_mul(dies),
+			threads,
+		}
+	}
+}
+
+/// Parses a QEMU size like `8G` or `8192M`, plain numbers are megabytes.
+pub fn parse_megabytes(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let (number, unit) = size.split_at(size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len()));
+	let number = number.parse::<u64>().ok()?;
+
+	match unit.to_ascii_uppercase().trim_end_matches('B') {
+		"K" => Some(number / 1024),
+		"" | "M" => Some(number),
+		"G" => Some(number * 1024),
+		"T" => Some(number * 1024 * 1024),
+		_ => None,
+	}
 }
 
 fn populate_bios_information(map: &mut SmBiosMap, table: Option<SMBiosInformation>) {
@@ -123,6 +420,19 @@ fn populate_oem_strings(map: &mut SmBiosMap) {
 	map.add_field(SmBiosType::OemStrings, "value", "Default string");
 }
 
+fn get_populated_memory_device(dmi: &SMBiosData) -> Option<SMBiosMemoryDevice<'_>> {
+	let table = dmi
+		.collect::<SMBiosMemoryDevice>()
+		.into_iter()
+		.find(|t| !matches!(t.size(), Some(MemorySize::NotInstalled) | None));
+
+	if table.is_none() {
+		log::warn!("no populated memory device found, using defaults for every field");
+	}
+
+	table
+}
+
 fn populate_memory_device(map: &mut SmBiosMap, table: Option<SMBiosMemoryDevice>) {
 	let bank = get_string_field(&table, |t| t.bank_locator(), "Bank 0");
 	let asset = get_string_field(&table, |t| t.asset_tag(), "Not Specified");
@@ -130,6 +440,7 @@ fn populate_memory_device(map: &mut SmBiosMap, table: Option<SMBiosMemoryDevice>
 	let manufacturer = get_string_field(&table, |t| t.manufacturer(), "OEM_VENDOR");
 	let serial = get_string_field(&table, |t| t.serial_number(), "OEM33162");
 	let loc_pfx = get_string_field(&table, |t| t.device_locator(), "DIMM 0");
+	let speed = get_field(&table, get_memory_speed, 3200_u16);
 
 	map.add_fields(
 		SmBiosType::MemoryDevice,
@@ -138,13 +449,23 @@ fn populate_memory_device(map: &mut SmBiosMap, table: Option<SMBiosMemoryDevice>
 			("asset", asset),
 			("part", part),
 			("manufacturer", manufacturer),
-			("speed", String::from("3200")),
+			("speed", speed.to_string()),
 			("serial", serial),
 			("loc_pfx", loc_pfx),
 		],
 	);
 }
 
+// the speed the memory actually runs at, rather than what it's rated for
+fn get_memory_speed(table: &SMBiosMemoryDevice) -> Option<u16> {
+	[table.configured_memory_speed(), table.speed()]
+		.into_iter()
+		.find_map(|speed| match speed {
+			Some(MemorySpeed::MTs(speed)) => Some(speed),
+			_ => None,
+		})
+}
+
 fn get_table<'a, T: SMBiosStruct<'a>>(dmi: &'a SMBiosData) -> Option<T> {
 	let table = dmi.first::<T>();
 
@@ -0,0 +1,198 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// A physical core and its SMT siblings.
+#[derive(Clone, Debug)]
+struct Core {
+	package: u32,
+	die: u32,
+	id: u32,
+	threads: Vec<usize>,
+	/// cores sharing an L3 cache, i.e. a CCD on Ryzen
+	l3: Option<(u32, u64)>,
+}
+
+impl Core {
+	fn ccd(&self) -> (u32, Option<u32>) {
+		(self.package, self.l3.map(|(id, _)| id))
+	}
+}
+
+/// Guest CPU layout, with the host CPU each vCPU is pinned to.
+#[derive(Debug)]
+pub struct Layout {
+	pub smp: String,
+	pub pins: Vec<usize>,
+}
+
+impl Layout {
+	/// Every host CPU used, in taskset's `--cpu-list` format.
+	pub fn affinity(&self) -> String {
+		let cpus = self.pins.iter().collect::<BTreeSet<_>>();
+		cpus.into_iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+	}
+}
+
+/// Picks `count` cores, preferring ones sharing an L3 cache, and the largest one at that.
+pub fn from_core_count(count: usize) -> io::Result<Layout> {
+	let cores = read_host()?;
+	let mut ccds = group_by_ccd(cores);
+
+	if let Some(ccd) = ccds
+		.iter()
+		.filter(|c| c.len() >= count)
+		.min_by_key(|c| Reverse(l3_size(c)))
+	{
+		return Ok(layout(&ccd[..count]));
+	}
+
+	let available = ccds.iter().map(Vec::len).sum::<usize>();
+
+	if available < count {
+		return Err(io::Error::other(format!(
+			"requested {count} cores, but the host only has {available}"
+		)));
+	}
+
+	// spread over as few CCDs as possible
+	ccds.sort_by_key(|c| Reverse(c.len()));
+	let chosen = ccds.into_iter().flatten().take(count).collect::<Vec<_>>();
+	log::warn!("no single L3 cache has {count} cores, the guest will span multiple");
+
+	Ok(layout(&chosen))
+}
+
+/// Uses the host CPUs in `affinity`, a list in taskset's `--cpu-list` format.
+pub fn from_affinity(affinity: &str) -> io::Result<Layout> {
+	let allowed = parse_cpu_list(affinity).ok_or_else(|| io::Error::other(format!("invalid cpu list {affinity}")))?;
+
+	let cores = read_host()?
+		.into_iter()
+		.filter_map(|mut core| {
+			core.threads.retain(|t| allowed.contains(t));
+			(!core.threads.is_empty()).then_some(core)
+		})
+		.collect::<Vec<_>>();
+
+	if cores.is_empty() {
+		return Err(io::Error::other(format!("cpu list {affinity} contains no online cpus")));
+	}
+
+	let chosen = group_by_ccd(cores).into_iter().flatten().collect::<Vec<_>>();
+	Ok(layout(&chosen))
+}
+
+fn layout(cores: &[Core]) -> Layout {
+	// QEMU requires the same thread count for every core
+	let threads = cores.iter().map(|c| c.threads.len()).min().unwrap_or(1);
+
+	let mut ccds = cores.iter().map(Core::ccd).collect::<Vec<_>>();
+	ccds.dedup();
+
+	// dies are the closest QEMU gets to CCDs, but they must all be the same size
+	let per_ccd = cores.len() / ccds.len();
+	let even = ccds
+		.iter()
+		.all(|ccd| cores.iter().filter(|c| c.ccd() == *ccd).count() == per_ccd);
+
+	let smp = match (ccds.len(), even) {
+		(dies @ 2.., true) => format!("sockets=1,dies={dies},cores={per_ccd},threads={threads}"),
+		_ => format!("sockets=1,cores={},threads={threads}", cores.len()),
+	};
+
+	// vCPUs are numbered by die, then core, then thread
+	let pins = cores
+		.iter()
+		.flat_map(|c| c.threads[..threads].iter().copied())
+		.collect();
+
+	Layout { smp, pins }
+}
+
+// Keeps the host's order, cores end up sorted by their first thread
+fn group_by_ccd(cores: Vec<Core>) -> Vec<Vec<Core>> {
+	let mut ccds: Vec<Vec<Core>> = vec![];
+
+	for core in cores {
+		match ccds.iter_mut().find(|c| c[0].ccd() == core.ccd()) {
+			Some(ccd) => ccd.push(core),
+			None => ccds.push(vec![core]),
+		}
+	}
+
+	ccds
+}
+
+fn l3_size(cores: &[Core]) -> u64 {
+	cores.first().and_then(|c| c.l3).map_or(0, |(_, size)| size)
+}
+
+fn read_host() -> io::Result<Vec<Core>> {
+	let online = fs::read_to_string(Path::new(CPU_DIR).join("online"))?;
+	let online = parse_cpu_list(online.trim()).ok_or_else(|| io::Error::other("unable to parse online cpus"))?;
+
+	let mut cores: Vec<Core> = vec![];
+
+	for cpu in online {
+		let dir = Path::new(CPU_DIR).join(format!("cpu{cpu}"));
+		let package = read_number(&dir.join("topology/physical_package_id"))?;
+		let id = read_number(&dir.join("topology/core_id"))?;
+
+		// only exposed by newer kernels
+		let die = read_number(&dir.join("topology/die_id")).unwrap_or(0);
+
+		match cores
+			.iter_mut()
+			.find(|c| (c.package, c.die, c.id) == (package, die, id))
+		{
+			Some(core) => core.threads.push(cpu),
+			None => cores.push(Core {
+				package,
+				die,
+				id,
+				threads: vec![cpu],
+				l3: read_l3(&dir),
+			}),
+		}
+	}
+
+	Ok(cores)
+}
+
+fn read_l3(cpu_dir: &Path) -> Option<(u32, u64)> {
+	let index = fs::read_dir(cpu_dir.join("cache"))
+		.ok()?
+		.filter_map(Result::ok)
+		.map(|e| e.path())
+		.find(|p| read_number(&p.join("level")).is_ok_and(|l| l == 3))?;
+
+	let id = read_number(&index.join("id")).ok()?;
+	let size = fs::read_to_string(index.join("size")).ok()?;
+	let size = size.trim().trim_end_matches('K').parse().ok()?;
+
+	Some((id, size))
+}
+
+fn read_number(path: &Path) -> io::Result<u32> {
+	fs::read_to_string(path)?
+		.trim()
+		.parse()
+		.map_err(|e| io::Error::other(format!("{}: {e}", path.display())))
+}
+
+/// Parses lists like `0-5,8-13`.
+pub fn parse_cpu_list(list: &str) -> Option<BTreeSet<usize>> {
+	let mut cpus = BTreeSet::new();
+
+	for range in list.split(',').filter(|r| !r.is_empty()) {
+		let (start, end) = range.split_once('-').unwrap_or((range, range));
+		cpus.extend(start.trim().parse::<usize>().ok()?..=end.trim().parse().ok()?);
+	}
+
+	Some(cpus)
+}
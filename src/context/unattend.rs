@@ -0,0 +1,243 @@
+use super::{iso, Unattend};
+
+// Storage drivers are needed to see the disk during setup, network drivers are carried over into the installation
+const DRIVERS: [&str; 3] = ["viostor", "vioscsi", "NetKVM"];
+
+// The driver ISO's drive letter depends on the number of disks and drives, try the likely ones
+const DRIVE_LETTERS: [char; 4] = ['D', 'E', 'F', 'G'];
+
+const COMPONENT_ATTRIBUTES: &str =
+	r#"processorArchitecture="amd64" publicKeyToken="31bf3856ad364e35" language="neutral" versionScope="nonSxS""#;
+
+/// Builds an ISO image containing the rendered `autounattend.xml`, which Windows setup picks up automatically.
+pub fn build_iso(unattend: &Unattend, uefi: bool) -> Vec<u8> {
+	iso::build("UNATTEND", "autounattend.xml", render(unattend, uefi).as_bytes())
+}
+
+/// Renders `autounattend.xml`, partitioning for UEFI (GPT) or legacy BIOS (MBR) boot.
+pub fn render(unattend: &Unattend, uefi: bool) -> String {
+	let locale = escape(&unattend.locale);
+	let input_locale = escape(&unattend.input_locale);
+	let timezone = escape(&unattend.timezone);
+	let user = escape(&unattend.user);
+	let password = escape(&unattend.password);
+	let disk = unattend.disk;
+
+	let international = format!(
+		"<InputLocale>{input_locale}</InputLocale>
+			<SystemLocale>{locale}</SystemLocale>
+			<UILanguage>{locale}</UILanguage>
+			<UserLocale>{locale}</UserLocale>"
+	);
+
+	let (partitions, system_partition) = match uefi {
+		true => (uefi_partitions(), 3),
+		false => (bios_partitions(), 2),
+	};
+
+	let product_key = match &unattend.product_key {
+		Some(key) => format!(
+			"<ProductKey>
+					<Key>{}</Key>
+					<WillShowUI>OnError</WillShowUI>
+				</ProductKey>",
+			escape(key)
+		),
+		None => String::new(),
+	};
+
+	format!(
+		r#"<?xml version="1.0" encoding="utf-8"?>
+<unattend xmlns="urn:schemas-microsoft-com:unattend" xmlns:wcm="http://schemas.microsoft.com/WMIConfig/2002/State">
+	<settings pass="windowsPE">
+		<component name="Microsoft-Windows-International-Core-WinPE" {COMPONENT_ATTRIBUTES}>
+			<SetupUILanguage>
+				<UILanguage>{locale}</UILanguage>
+			</SetupUILanguage>
+			{international}
+		</component>
+		<component name="Microsoft-Windows-PnpCustomizationsWinPE" {COMPONENT_ATTRIBUTES}>
+			<DriverPaths>
+{driver_paths}
+			</DriverPaths>
+		</component>
+		<component name="Microsoft-Windows-Setup" {COMPONENT_ATTRIBUTES}>
+			<DiskConfiguration>
+				<Disk wcm:action="add">
+					<DiskID>{disk}</DiskID>
+					<WillWipeDisk>true</WillWipeDisk>
+{partitions}
+				</Disk>
+			</DiskConfiguration>
+			<ImageInstall>
+				<OSImage>
+					<InstallTo>
+						<DiskID>{disk}</DiskID>
+						<PartitionID>{system_partition}</PartitionID>
+					</InstallTo>
+				</OSImage>
+			</ImageInstall>
+			<UserData>
+				<AcceptEula>true</AcceptEula>
+				{product_key}
+			</UserData>
+		</component>
+	</settings>
+	<settings pass="oobeSystem">
+		<component name="Microsoft-Windows-International-Core" {COMPONENT_ATTRIBUTES}>
+			{international}
+		</component>
+		<component name="Microsoft-Windows-Shell-Setup" {COMPONENT_ATTRIBUTES}>
+			<TimeZone>{timezone}</TimeZone>
+			<OOBE>
+				<HideEULAPage>true</HideEULAPage>
+				<HideOnlineAccountScreens>true</HideOnlineAccountScreens>
+				<HideWirelessSetupInOOBE>true</HideWirelessSetupInOOBE>
+				<ProtectYourPC>3</ProtectYourPC>
+			</OOBE>
+			<UserAccounts>
+				<LocalAccounts>
+					<LocalAccount wcm:action="add">
+						<Name>{user}</Name>
+						<Group>Administrators</Group>
+						<Password>
+							<Value>{password}</Value>
+							<PlainText>true</PlainText>
+						</Password>
+					</LocalAccount>
+				</LocalAccounts>
+			</UserAccounts>
+		</component>
+	</settings>
+</unattend>
+"#,
+		driver_paths = driver_paths(&unattend.driver_version),
+	)
+}
+
+fn driver_paths(version: &str) -> String {
+	let version = escape(version);
+	let mut paths = vec![];
+
+	for letter in DRIVE_LETTERS {
+		for driver in DRIVERS {
+			paths.push(format!(
+				r#"				<PathAndCredentials wcm:action="add" wcm:keyValue="{}">
+					<Path>{letter}:\{driver}\{version}\amd64</Path>
+				</PathAndCredentials>"#,
+				paths.len() + 1
+			));
+		}
+	}
+
+	paths.join("\n")
+}
+
+// EFI system partition, Microsoft reserved partition, Windows
+fn uefi_partitions() -> String {
+	partitions(&[
+		Partition::new("EFI", Some(100), Some(("FAT32", "System"))),
+		Partition::new("MSR", Some(16), None),
+		Partition::new("Primary", None, Some(("NTFS", "Windows"))),
+	])
+}
+
+// System reserved partition, Windows
+fn bios_partitions() -> String {
+	let system_reserved = Partition {
+		active: true,
+		..Partition::new("Primary", Some(100), Some(("NTFS", "System Reserved")))
+	};
+
+	partitions(&[
+		system_reserved,
+		Partition::new("Primary", None, Some(("NTFS", "Windows"))),
+	])
+}
+
+struct Partition {
+	kind: &'static str,
+	/// Size in MB, the partition extends over the rest of the disk if unset
+	size: Option<u32>,
+	/// File system and label
+	format: Option<(&'static str, &'static str)>,
+	active: bool,
+}
+
+impl Partition {
+	fn new(kind: &'static str, size: Option<u32>, format: Option<(&'static str, &'static str)>) -> Self {
+		Self {
+			kind,
+			size,
+			format,
+			active: false,
+		}
+	}
+}
+
+// The last partition is assigned C:
+fn partitions(partitions: &[Partition]) -> String {
+	let mut create = vec![];
+	let mut modify = vec![];
+
+	for (index, partition) in partitions.iter().enumerate() {
+		let order = index + 1;
+		let kind = partition.kind;
+		let size = match partition.size {
+			Some(size) => format!("<Size>{size}</Size>"),
+			None => String::from("<Extend>true</Extend>"),
+		};
+
+		create.push(format!(
+			r#"						<CreatePartition wcm:action="add">
+							<Order>{order}</Order>
+							<Type>{kind}</Type>
+							{size}
+						</CreatePartition>"#
+		));
+
+		let mut options = vec![];
+
+		if let Some((filesystem, label)) = partition.format {
+			options.push(format!("<Format>{filesystem}</Format>"));
+			options.push(format!("<Label>{label}</Label>"));
+		}
+
+		if partition.active {
+			options.push(String::from("<Active>true</Active>"));
+		}
+
+		if order == partitions.len() {
+			options.push(String::from("<Letter>C</Letter>"));
+		}
+
+		modify.push(format!(
+			r#"						<ModifyPartition wcm:action="add">
+							<Order>{order}</Order>
+							<PartitionID>{order}</PartitionID>
+							{}
+						</ModifyPartition>"#,
+			options.join("\n\t\t\t\t\t\t\t")
+		));
+	}
+
+	format!(
+		"					<CreatePartitions>
+{}
+					</CreatePartitions>
+					<ModifyPartitions>
+{}
+					</ModifyPartitions>",
+		create.join("\n"),
+		modify.join("\n")
+	)
+}
+
+fn escape(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&apos;")
+}
@@ -58,11 +58,37 @@ pub struct TmpFileWriter {
 
 impl TmpFileWriter {
 	pub fn add(&mut self, path: impl Into<PathBuf>, uid: Uid, gid: Gid, mode: Mode) -> &'_ mut Self {
+		self.add_with_contents(path, uid, gid, mode, vec![])
+	}
+
+	pub fn add_with_contents(
+		&mut self,
+		path: impl Into<PathBuf>,
+		uid: Uid,
+		gid: Gid,
+		mode: Mode,
+		contents: Vec<u8>,
+	) -> &'_ mut Self {
 		self.files.push(TmpFile {
 			path: path.into(),
 			uid,
 			gid,
 			mode,
+			contents,
+			private: false,
+		});
+		self
+	}
+
+	/// Adds a file only root can read, which is deleted once the VM has stopped.
+	pub fn add_private(&mut self, path: impl Into<PathBuf>, contents: Vec<u8>) -> &'_ mut Self {
+		self.files.push(TmpFile {
+			path: path.into(),
+			uid: Uid::from_raw(0),
+			gid: Gid::from_raw(0),
+			mode: Mode::from_bits_truncate(0o600),
+			contents,
+			private: true,
 		});
 		self
 	}
@@ -1,6 +1,7 @@
-use cli::{Command, Options};
+use cli::{Command, Options, SmbiosAction, SnapshotAction};
 use context::{Context, ContextBuilder};
 use nix::unistd::Uid;
+use std::path::PathBuf;
 
 mod cli;
 mod config;
@@ -17,9 +18,23 @@ fn main() {
 	}
 
 	match cli.command {
-		Command::Run { config, skip_attach } => run(config, skip_attach),
+		Command::Run {
+			config,
+			skip_attach,
+			ephemeral,
+			keep_overlay,
+		} => run(config, skip_attach, ephemeral, keep_overlay),
+		Command::Install { config, iso, drivers } => install(config, iso, drivers),
 		Command::Detach { config } => detach(config),
 		Command::Attach { config } => attach(config),
+		Command::Snapshot { config, action } => snapshot(config, action),
+		Command::ResetNvram { config } => reset_nvram(config),
+		Command::Smbios { action } => smbios(action),
+		Command::DumpRom {
+			address,
+			output,
+			strip_nvidia_header,
+		} => dump_rom(&address, output, strip_nvidia_header),
 	}
 }
 
@@ -35,17 +50,82 @@ fn attach(config: Options) {
 	runner::reattach_devices(&context);
 }
 
-fn run(config: Options, skip_attach: bool) {
-	let context = get_context(&config);
+fn run(config: Options, skip_attach: bool, ephemeral: bool, keep_overlay: bool) {
+	let mut builder = get_builder(&config);
+
+	if ephemeral {
+		builder.ephemeral(keep_overlay);
+	}
+
+	let context = build_context(builder);
 
 	if runner::run(context, skip_attach).is_ok() {
 		log::info!("exit successful");
 	}
 }
 
+fn install(config: Options, iso: PathBuf, drivers: Option<PathBuf>) {
+	let mut builder = get_builder(&config);
+	builder.install(iso, drivers);
+
+	let context = build_context(builder);
+
+	if runner::run(context, false).is_ok() {
+		log::info!("exit successful");
+	}
+}
+
+fn snapshot(config: Options, action: SnapshotAction) {
+	let context = get_context(&config);
+
+	let result = match action {
+		SnapshotAction::Create { name, external } => runner::create_snapshot(&context, &name, external),
+		SnapshotAction::List => runner::list_snapshots(&context),
+		SnapshotAction::Revert { name, external } => runner::revert_snapshot(&context, &name, external),
+		SnapshotAction::Delete { name, external } => runner::delete_snapshot(&context, &name, external),
+	};
+
+	if result.is_err() {
+		std::process::exit(1);
+	}
+}
+
+fn reset_nvram(config: Options) {
+	let context = get_context(&config);
+
+	runner::reset_nvram(&context).ok();
+}
+
+fn smbios(action: SmbiosAction) {
+	match action {
+		SmbiosAction::Dump {
+			output,
+			exclude_type,
+			uuid,
+		} => runner::dump_smbios(&output, &exclude_type, uuid.as_deref()),
+	}
+	.ok();
+}
+
+fn dump_rom(address: &str, output: Option<PathBuf>, strip_nvidia_header: bool) {
+	let output = output.unwrap_or_else(|| PathBuf::from(format!("{address}.rom")));
+
+	runner::dump_rom(address, &output, strip_nvidia_header).ok();
+}
+
 fn get_context(config: &Options) -> Context {
+	build_context(get_builder(config))
+}
+
+fn get_builder(config: &Options) -> ContextBuilder {
 	let mut builder = ContextBuilder::default();
+	builder.profile(config.profile.name());
 	config::configure(&mut builder, config);
+
+	builder
+}
+
+fn build_context(builder: ContextBuilder) -> Context {
 	log::debug!("{builder:?}");
 
 	let context = builder.build();
@@ -0,0 +1,60 @@
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a daemon listening on `socket` and waits for the socket to appear, QEMU fails right away otherwise.
+pub fn spawn(cmd: &mut Command, socket: &Path) -> Result<Child> {
+	let name = cmd.get_program().to_string_lossy().into_owned();
+
+	if let Some(parent) = socket.parent() {
+		fs::create_dir_all(parent)?;
+	}
+
+	// most refuse to reuse a stale socket
+	remove_socket(socket)?;
+
+	let mut child = cmd.spawn().with_context(|| format!("unable to start {name}"))?;
+	let start = Instant::now();
+
+	while !socket.exists() {
+		if let Some(status) = child.try_wait()? {
+			bail!("{name} exited prematurely: {status}");
+		}
+
+		if start.elapsed() > STARTUP_TIMEOUT {
+			stop(child)?;
+			bail!("{name} didn't create {} in time", socket.display());
+		}
+
+		thread::sleep(Duration::from_millis(50));
+	}
+
+	Ok(child)
+}
+
+pub fn stop(mut child: Child) -> Result<()> {
+	let pid = Pid::from_raw(child.id().try_into()?);
+
+	// it may have exited along with QEMU already
+	if child.try_wait()?.is_none() {
+		signal::kill(pid, Signal::SIGTERM)?;
+	}
+
+	child.wait()?;
+	Ok(())
+}
+
+pub fn remove_socket(socket: &Path) -> Result<()> {
+	match fs::remove_file(socket) {
+		Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+		_ => Ok(()),
+	}
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+const VTCONSOLE_DIR: &str = "/sys/class/vtconsole";
+const PLATFORM_DRIVER_DIR: &str = "/sys/bus/platform/drivers";
+const FRAMEBUFFER_DRIVERS: [&str; 3] = ["efi-framebuffer", "simple-framebuffer", "vesa-framebuffer"];
+
+// Persisted so a separate `vfio-run attach` restores exactly what `detach` released
+const STATE_DIR: &str = "/run/vfio-run";
+const STATE_FILE: &str = "/run/vfio-run/released-framebuffer";
+
+/// A console or framebuffer device that was unbound from the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Released {
+	VtConsole(String),
+	PlatformDevice { driver: String, device: String },
+}
+
+/// Unbinds framebuffer consoles, then the framebuffer devices backing them.
+/// Every successful step is recorded in `released`, even if a later one fails.
+pub fn release(released: &mut Vec<Released>) -> Result<()> {
+	for console in bound_vtconsoles()? {
+		log::debug!("unbinding {console}");
+		write(&Path::new(VTCONSOLE_DIR).join(&console).join("bind"), "0")?;
+		released.push(Released::VtConsole(console));
+	}
+
+	for driver in FRAMEBUFFER_DRIVERS {
+		for device in bound_platform_devices(driver)? {
+			log::debug!("unbinding {device} from {driver}");
+			write(&Path::new(PLATFORM_DRIVER_DIR).join(driver).join("unbind"), &device)?;
+
+			released.push(Released::PlatformDevice {
+				driver: driver.to_owned(),
+				device,
+			});
+		}
+	}
+
+	Ok(())
+}
+
+/// Rebinds a single released console or device.
+pub fn restore(released: &Released) -> Result<()> {
+	match released {
+		Released::VtConsole(console) => {
+			log::debug!("rebinding {console}");
+			write(&Path::new(VTCONSOLE_DIR).join(console).join("bind"), "1")
+		}
+		Released::PlatformDevice { driver, device } => {
+			log::debug!("rebinding {device} to {driver}");
+			write(&Path::new(PLATFORM_DRIVER_DIR).join(driver).join("bind"), device)
+		}
+	}
+}
+
+/// Adds `released` to the saved state. Entries from an earlier detach are kept, they are still needed to restore the console.
+pub fn save(released: &[Released]) -> Result<()> {
+	let mut state = read_saved()?;
+
+	for released in released {
+		if !state.contains(released) {
+			state.push(released.clone());
+		}
+	}
+
+	write_saved(&state)
+}
+
+/// Removes `restored` from the saved state, once they're back on the host.
+pub fn forget(restored: &[Released]) -> Result<()> {
+	let mut state = read_saved()?;
+	state.retain(|r| !restored.contains(r));
+
+	write_saved(&state)
+}
+
+/// Loads and removes the saved state. Nothing was released if there is none.
+pub fn take_saved() -> Result<Vec<Released>> {
+	let state = read_saved()?;
+
+	if let Err(e) = fs::remove_file(STATE_FILE) {
+		if e.kind() != ErrorKind::NotFound {
+			return Err(e).with_context(|| format!("unable to remove {STATE_FILE}"));
+		}
+	}
+
+	Ok(state)
+}
+
+fn read_saved() -> Result<Vec<Released>> {
+	let state = match fs::read_to_string(STATE_FILE) {
+		Ok(state) => state,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e).with_context(|| format!("unable to read {STATE_FILE}")),
+	};
+
+	state.lines().map(parse_line).collect()
+}
+
+// an empty state is the same as none, don't leave an empty file behind
+fn write_saved(state: &[Released]) -> Result<()> {
+	if state.is_empty() {
+		return match fs::remove_file(STATE_FILE) {
+			Err(e) if e.kind() != ErrorKind::NotFound => {
+				Err(e).with_context(|| format!("unable to remove {STATE_FILE}"))
+			}
+			_ => Ok(()),
+		};
+	}
+
+	let state = state
+		.iter()
+		.map(|r| match r {
+			Released::VtConsole(console) => format!("vtconsole {console}\n"),
+			Released::PlatformDevice { driver, device } => format!("platform {driver} {device}\n"),
+		})
+		.collect::<String>();
+
+	fs::create_dir_all(STATE_DIR)?;
+	fs::write(STATE_FILE, state).with_context(|| format!("unable to write {STATE_FILE}"))
+}
+
+fn parse_line(line: &str) -> Result<Released> {
+	match line.split(' ').collect::<Vec<_>>()[..] {
+		["vtconsole", console] => Ok(Released::VtConsole(console.to_owned())),
+		["platform", driver, device] => Ok(Released::PlatformDevice {
+			driver: driver.to_owned(),
+			device: device.to_owned(),
+		}),
+		_ => bail!("malformed line in {STATE_FILE}: {line}"),
+	}
+}
+
+// Only framebuffer consoles hold on to the GPU, leave the dummy console alone
+fn bound_vtconsoles() -> Result<Vec<String>> {
+	let mut consoles = vec![];
+
+	for entry in fs::read_dir(VTCONSOLE_DIR)? {
+		let path = entry?.path();
+		let bound = fs::read_to_string(path.join("bind"))?.trim() == "1";
+		let framebuffer = fs::read_to_string(path.join("name"))?.contains("frame buffer");
+
+		if bound && framebuffer {
+			consoles.extend(path.file_name().map(|n| n.to_string_lossy().into_owned()));
+		}
+	}
+
+	consoles.sort();
+	Ok(consoles)
+}
+
+fn bound_platform_devices(driver: &str) -> Result<Vec<String>> {
+	let driver_dir = Path::new(PLATFORM_DRIVER_DIR).join(driver);
+
+	// driver not built or not loaded, nothing to release
+	if !driver_dir.exists() {
+		return Ok(vec![]);
+	}
+
+	let prefix = format!("{driver}.");
+	let mut devices = vec![];
+
+	for entry in fs::read_dir(driver_dir)? {
+		let name = entry?.file_name().to_string_lossy().into_owned();
+
+		if name.starts_with(&prefix) {
+			devices.push(name);
+		}
+	}
+
+	devices.sort();
+	Ok(devices)
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+	fs::write(path, value).with_context(|| format!("unable to write '{value}' to {}", path.display()))
+}
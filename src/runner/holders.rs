@@ -0,0 +1,148 @@
+use anyhow::Result;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A process holding an open handle on one of a set of device nodes.
+#[derive(Debug)]
+pub struct Holder {
+	pub pid: u32,
+	pub cmdline: String,
+}
+
+impl Display for Holder {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {}", self.pid, self.cmdline)
+	}
+}
+
+// Shared by all NVIDIA GPUs, any handle on these keeps the driver loaded
+const NVIDIA_SHARED_NODES: [&str; 4] = [
+	"/dev/nvidiactl",
+	"/dev/nvidia-modeset",
+	"/dev/nvidia-uvm",
+	"/dev/nvidia-uvm-tools",
+];
+
+/// All device nodes through which processes may be using the specified PCI device.
+pub fn gpu_nodes(address: &str) -> Vec<PathBuf> {
+	let mut nodes = drm_nodes(address);
+	nodes.extend(nvidia_nodes(address));
+	nodes
+}
+
+/// DRM nodes (`/dev/dri/card*`, `/dev/dri/renderD*`) belonging to the specified PCI device.
+pub fn drm_nodes(address: &str) -> Vec<PathBuf> {
+	let Ok(entries) = fs::read_dir(format!("/sys/bus/pci/devices/{address}/drm")) else {
+		return vec![];
+	};
+
+	entries
+		.filter_map(Result::ok)
+		.map(|e| e.file_name().to_string_lossy().into_owned())
+		.filter(|name| name.starts_with("card") || name.starts_with("renderD"))
+		.map(|name| Path::new("/dev/dri").join(name))
+		.collect()
+}
+
+/// `/dev/nvidia<minor>` of the specified PCI device plus the shared nodes, if it's driven by the NVIDIA driver.
+pub fn nvidia_nodes(address: &str) -> Vec<PathBuf> {
+	let Ok(information) = fs::read_to_string(format!("/proc/driver/nvidia/gpus/{address}/information")) else {
+		return vec![];
+	};
+
+	let minor = information
+		.lines()
+		.find_map(|l| l.strip_prefix("Device Minor:"))
+		.map(str::trim);
+
+	let mut nodes = NVIDIA_SHARED_NODES.map(PathBuf::from).to_vec();
+	nodes.extend(minor.map(|m| PathBuf::from(format!("/dev/nvidia{m}"))));
+	nodes
+}
+
+/// Scans the open file descriptors and memory mappings of all processes for handles on `nodes`.
+/// Processes we aren't allowed to inspect are skipped.
+pub fn find(nodes: &[PathBuf]) -> Vec<Holder> {
+	if nodes.is_empty() {
+		return vec![];
+	}
+
+	processes()
+		.filter(|&pid| holds_fd(pid, nodes) || maps_node(pid, nodes))
+		.map(|pid| Holder {
+			pid,
+			cmdline: cmdline(pid),
+		})
+		.collect()
+}
+
+pub fn terminate(holder: &Holder) -> Result<()> {
+	signal::kill(Pid::from_raw(holder.pid as i32), Signal::SIGTERM)?;
+	Ok(())
+}
+
+pub fn is_running(holder: &Holder) -> bool {
+	is_alive(holder.pid)
+}
+
+/// Zombies have released everything already, they only wait for their parent to reap them.
+pub fn is_alive(pid: u32) -> bool {
+	let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat")) else {
+		return false;
+	};
+
+	// the command name may contain spaces and parentheses, the state follows the last one
+	let state = stat
+		.rsplit_once(')')
+		.and_then(|(_, rest)| rest.split_whitespace().next());
+	state != Some("Z")
+}
+
+fn processes() -> impl Iterator<Item = u32> {
+	let own_pid = std::process::id();
+
+	fs::read_dir("/proc")
+		.into_iter()
+		.flatten()
+		.filter_map(Result::ok)
+		.filter_map(|e| e.file_name().to_str()?.parse().ok())
+		.filter(move |&pid| pid != own_pid)
+}
+
+fn holds_fd(pid: u32, nodes: &[PathBuf]) -> bool {
+	let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+		return false;
+	};
+
+	fds.filter_map(Result::ok)
+		.filter_map(|fd| fs::read_link(fd.path()).ok())
+		.any(|target| nodes.contains(&target))
+}
+
+fn maps_node(pid: u32, nodes: &[PathBuf]) -> bool {
+	let Ok(maps) = fs::read_to_string(format!("/proc/{pid}/maps")) else {
+		return false;
+	};
+
+	// the pathname is the sixth column, anonymous mappings have none
+	maps.lines()
+		.filter_map(|l| l.split_whitespace().nth(5))
+		.any(|path| nodes.iter().any(|n| n.as_os_str() == path))
+}
+
+fn cmdline(pid: u32) -> String {
+	let cmdline = fs::read(format!("/proc/{pid}/cmdline")).unwrap_or_default();
+	let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ").trim().to_owned();
+
+	if !cmdline.is_empty() {
+		return cmdline;
+	}
+
+	// kernel threads and zombies have no command line
+	fs::read_to_string(format!("/proc/{pid}/comm"))
+		.map(|comm| format!("[{}]", comm.trim()))
+		.unwrap_or_default()
+}
@@ -0,0 +1,30 @@
+use super::util::run_command;
+use anyhow::Result;
+use nix::unistd::Uid;
+use std::process::Command;
+
+pub fn create_tap(name: &str, owner: Uid) -> Result<()> {
+	run_command(
+		Command::new("ip")
+			.args(["tuntap", "add", "dev", name, "mode", "tap", "user"])
+			.arg(owner.to_string()),
+	)
+}
+
+pub fn create_macvtap(name: &str, parent: &str, mac: &str) -> Result<()> {
+	run_command(Command::new("ip").args([
+		"link", "add", "link", parent, "name", name, "address", mac, "type", "macvtap", "mode", "bridge",
+	]))
+}
+
+pub fn set_master(name: &str, bridge: &str) -> Result<()> {
+	run_command(Command::new("ip").args(["link", "set", name, "master", bridge]))
+}
+
+pub fn set_up(name: &str) -> Result<()> {
+	run_command(Command::new("ip").args(["link", "set", name, "up"]))
+}
+
+pub fn delete(name: &str) -> Result<()> {
+	run_command(Command::new("ip").args(["link", "delete", name]))
+}
@@ -1,33 +1,135 @@
-use crate::context::{Context, TmpFile};
+use crate::context::{Context, Daemon, NetDevice, Nvram, Overlay, PciDevice, PortForward, TmpFile};
 use anyhow::Result;
+use nix::libc;
+use std::borrow::Borrow;
 use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 mod cpupower;
+mod daemon;
+mod framebuffer;
+mod holders;
+mod ip;
 mod modprobe;
+mod netdev;
+mod passt;
 mod pat_dealloc;
+mod pci;
+mod ports;
 mod qemu;
+mod qemu_img;
+mod rom;
+mod smbios;
+mod snapshot;
+mod swtpm;
+mod systemctl;
+mod taskset;
 mod util;
 mod virsh;
+mod virtiofsd;
+
+const DRM_RELEASE_TIMEOUT: Duration = Duration::from_secs(15);
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROMPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 pub fn run(context: Context, skip_attach: bool) -> Result<(), ()> {
+	check_forwards(&context.forwards)?;
 	set_governor(context.cpu_governor.as_deref())?;
-	create_tmp_files(&context.tmp_files)?;
+
+	let result = create_tmp_files(&context.tmp_files).and_then(|()| run_vm(&context, skip_attach));
+	remove_private_files(&context.tmp_files);
+
+	result
+}
+
+fn run_vm(context: &Context, skip_attach: bool) -> Result<(), ()> {
+	create_nvram(context.nvram.as_ref())?;
+	create_overlays(&context.overlays)?;
+
+	let Ok(handles) = create_net_devices(&context.net_devices) else {
+		discard_overlays(&context.overlays);
+		return Err(());
+	};
+
+	let Ok(daemons) = start_daemons(&context.daemons) else {
+		remove_net_devices(&context.net_devices, handles);
+		discard_overlays(&context.overlays);
+		return Err(());
+	};
 
 	ignore_sigint();
-	detach_devices(&context)?;
+
+	if detach_devices(context).is_err() {
+		stop_daemons(&context.daemons, daemons);
+		remove_net_devices(&context.net_devices, handles);
+		discard_overlays(&context.overlays);
+		return Err(());
+	}
 
 	log::info!("starting qemu");
 
-	let result = qemu::run_qemu(&context);
+	let result = qemu::run_qemu(context, &handles.fds);
 
 	if let Err(e) = result {
 		log::error!("error running qemu: {e}");
 	}
 
 	if !skip_attach {
-		reattach_devices(&context);
+		reattach_devices(context);
 	}
 
+	stop_daemons(&context.daemons, daemons);
+	remove_net_devices(&context.net_devices, handles);
+	discard_overlays(&context.overlays);
+
+	Ok(())
+}
+
+pub fn dump_rom(address: &str, output: &Path, strip_nvidia_header: bool) -> Result<(), ()> {
+	log::info!("dumping rom of {address}");
+
+	if let Err(e) = try_dump_rom(address, output, strip_nvidia_header) {
+		log::error!("dumping rom {e}");
+		return Err(());
+	}
+
+	log::info!("rom written to {}", output.display());
+	Ok(())
+}
+
+pub fn dump_smbios(output: &Path, exclude: &[u8], uuid: Option<&str>) -> Result<(), ()> {
+	log::info!("dumping smbios tables");
+
+	match smbios::dump(output, exclude, uuid) {
+		Ok(count) => log::info!("{count} structures written to {}", output.display()),
+		Err(e) => {
+			log::error!("dumping smbios tables {e}");
+			return Err(());
+		}
+	}
+
+	Ok(())
+}
+
+fn try_dump_rom(address: &str, output: &Path, strip_nvidia_header: bool) -> Result<()> {
+	let rom = rom::read(address)?;
+
+	let rom = match strip_nvidia_header {
+		true => rom::strip_nvidia_header(&rom)?,
+		false => &rom,
+	};
+
+	rom::validate(rom)?;
+	fs::write(output, rom)?;
+
 	Ok(())
 }
 
@@ -47,13 +149,29 @@ fn set_governor(governor: Option<&str>) -> Result<(), ()> {
 }
 
 // We ignore SIGINT, let the wrapped QEMU process handle it
-// and then clean up after it exits
+// and then clean up after it exits. Prompts check for it themselves
 fn ignore_sigint() {
-	if let Err(err) = ctrlc::set_handler(|| ()) {
+	if let Err(err) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
 		log::warn!("error setting SIGINT handler: {err}");
 	}
 }
 
+fn check_forwards(forwards: &[PortForward]) -> Result<(), ()> {
+	let mut result = Ok(());
+
+	// report all collisions at once
+	for forward in forwards.iter().filter(|f| ports::is_in_use(f)) {
+		log::error!(
+			"host port {}/{} is already in use",
+			forward.protocol.as_str(),
+			forward.host_port
+		);
+		result = Err(());
+	}
+
+	result
+}
+
 fn create_tmp_files(files: &[TmpFile]) -> Result<(), ()> {
 	for file in files {
 		if let Err(err) = create_tmp_file(file) {
@@ -65,12 +183,293 @@ fn create_tmp_files(files: &[TmpFile]) -> Result<(), ()> {
 	Ok(())
 }
 
+fn remove_private_files(files: &[TmpFile]) {
+	for file in files.iter().filter(|f| f.private) {
+		log::debug!("deleting {}", file.path.display());
+
+		// not created if an earlier file failed
+		match fs::remove_file(&file.path) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => log::error!("error deleting {}: {e}", file.path.display()),
+			_ => (),
+		}
+	}
+}
+
 fn create_tmp_file(tmp_file: &TmpFile) -> Result<()> {
 	fs::remove_file(&tmp_file.path).ok();
 
-	let file = File::create(&tmp_file.path)?;
+	if let Some(parent) = tmp_file.path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+
+	let mut file = File::create(&tmp_file.path)?;
 	nix::unistd::fchown(&file, Some(tmp_file.uid), Some(tmp_file.gid))?;
 	nix::sys::stat::fchmod(&file, tmp_file.mode)?;
+	file.write_all(&tmp_file.contents)?;
+
+	Ok(())
+}
+
+fn create_nvram(nvram: Option<&Nvram>) -> Result<(), ()> {
+	let Some(nvram) = nvram else {
+		return Ok(());
+	};
+
+	if nvram.path.exists() {
+		return Ok(());
+	}
+
+	log::info!("creating nvram from {}", nvram.template.display());
+
+	if let Err(e) = copy_nvram(nvram) {
+		log::error!("creating nvram {e}");
+		return Err(());
+	}
+
+	Ok(())
+}
+
+pub fn reset_nvram(context: &Context) -> Result<(), ()> {
+	let Some(nvram) = &context.nvram else {
+		log::error!("profile doesn't use a separate nvram, see ovmf_pflash");
+		return Err(());
+	};
+
+	log::info!("resetting {} to {}", nvram.path.display(), nvram.template.display());
+
+	if let Err(e) = copy_nvram(nvram) {
+		log::error!("resetting nvram {e}");
+		return Err(());
+	}
+
+	Ok(())
+}
+
+fn copy_nvram(nvram: &Nvram) -> Result<()> {
+	if let Some(parent) = nvram.path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+
+	// templates are usually read-only, the copy must not be
+	fs::copy(&nvram.template, &nvram.path)?;
+	fs::set_permissions(&nvram.path, fs::Permissions::from_mode(0o644))?;
+
+	Ok(())
+}
+
+fn create_overlays(overlays: &[Overlay]) -> Result<(), ()> {
+	if overlays.is_empty() {
+		return Ok(());
+	}
+
+	log::info!("creating disk overlays");
+
+	for (index, overlay) in overlays.iter().enumerate() {
+		log::debug!(
+			"creating {} on top of {}",
+			overlay.path.display(),
+			overlay.backing_file.display()
+		);
+		fs::remove_file(&overlay.path).ok();
+
+		if let Some(parent) = overlay.path.parent() {
+			fs::create_dir_all(parent).ok();
+		}
+
+		if let Err(e) = qemu_img::create_overlay(&overlay.path, &overlay.backing_file, overlay.backing_format) {
+			log::error!("creating overlay {e}");
+
+			// the VM never ran, so there is nothing worth keeping in them
+			overlays[..index].iter().for_each(remove_overlay);
+			return Err(());
+		}
+	}
+
+	Ok(())
+}
+
+fn create_net_devices(devices: &[NetDevice]) -> Result<netdev::Handles, ()> {
+	let mut handles = netdev::Handles::default();
+
+	if devices.is_empty() {
+		return Ok(handles);
+	}
+
+	log::info!("creating network devices");
+
+	for (index, device) in devices.iter().enumerate() {
+		log::debug!("creating {}", netdev::name(device));
+
+		if let Err(e) = netdev::create(device, &mut handles) {
+			log::error!("creating network device {e}");
+			remove_net_devices(&devices[..=index], handles);
+			return Err(());
+		}
+	}
+
+	Ok(handles)
+}
+
+fn remove_net_devices(devices: &[NetDevice], handles: netdev::Handles) {
+	drop(handles.fds);
+
+	for helper in handles.helpers {
+		if let Err(e) = daemon::stop(helper) {
+			log::error!("stopping network helper {e}");
+		}
+	}
+
+	for device in devices {
+		log::debug!("removing {}", netdev::name(device));
+
+		// keep going on error, attempt removing the rest as well
+		if let Err(e) = netdev::remove(device) {
+			log::error!("removing network device {e}");
+		}
+	}
+}
+
+fn start_daemons(daemons: &[Daemon]) -> Result<Vec<Child>, ()> {
+	let mut children = vec![];
+
+	for daemon in daemons {
+		log::info!("starting {}", daemon.name());
+
+		let result = match daemon {
+			Daemon::Virtiofsd { socket, path } => virtiofsd::spawn(socket, path),
+			Daemon::Swtpm { socket, state } => swtpm::spawn(socket, state),
+		};
+
+		match result {
+			Ok(child) => children.push(child),
+			Err(e) => {
+				log::error!("starting daemon {e}");
+				stop_daemons(daemons, children);
+				return Err(());
+			}
+		}
+	}
+
+	Ok(children)
+}
+
+// only the first few daemons may have been started if startup failed
+fn stop_daemons(daemons: &[Daemon], children: Vec<Child>) {
+	for (daemon, child) in daemons.iter().zip(children) {
+		log::debug!("stopping {}", daemon.name());
+
+		if let Err(e) = daemon::stop(child) {
+			log::error!("stopping daemon {e}");
+		}
+
+		if let Err(e) = daemon::remove_socket(daemon.socket()) {
+			log::error!("removing daemon socket {e}");
+		}
+	}
+}
+
+fn discard_overlays(overlays: &[Overlay]) {
+	for overlay in overlays {
+		if !overlay.discard {
+			log::info!("keeping overlay {}", overlay.path.display());
+			continue;
+		}
+
+		remove_overlay(overlay);
+	}
+}
+
+fn remove_overlay(overlay: &Overlay) {
+	log::debug!("deleting {}", overlay.path.display());
+
+	if let Err(e) = fs::remove_file(&overlay.path) {
+		log::error!("error deleting overlay {}: {e}", overlay.path.display());
+	}
+}
+
+pub fn create_snapshot(context: &Context, name: &str, external: bool) -> Result<(), ()> {
+	ensure_images_unused(&context.images)?;
+
+	for_each_image(context, "creating snapshot", |image| match external {
+		true => snapshot::create_external(image, name),
+		false => qemu_img::create_snapshot(image, name),
+	})
+}
+
+pub fn revert_snapshot(context: &Context, name: &str, external: bool) -> Result<(), ()> {
+	ensure_images_unused(&context.images)?;
+
+	for_each_image(context, "reverting snapshot", |image| match external {
+		true => snapshot::revert_external(image, name),
+		false => qemu_img::revert_snapshot(image, name),
+	})
+}
+
+pub fn delete_snapshot(context: &Context, name: &str, external: bool) -> Result<(), ()> {
+	ensure_images_unused(&context.images)?;
+
+	for_each_image(context, "deleting snapshot", |image| match external {
+		true => snapshot::delete_external(image, name),
+		false => qemu_img::delete_snapshot(image, name),
+	})
+}
+
+pub fn list_snapshots(context: &Context) -> Result<(), ()> {
+	for_each_image(context, "listing snapshots", |image| {
+		let internal = qemu_img::list_snapshots(image)?;
+		let external = snapshot::list_external(image)?;
+
+		println!(
+			"{}:
+{}",
+			image.display(),
+			internal.trim_end()
+		);
+
+		if !external.is_empty() {
+			println!("External snapshots, newest first: {}", external.join(", "));
+		}
+
+		Ok(())
+	})
+}
+
+// qemu-img rewrites the image underneath a running VM, which corrupts it
+fn ensure_images_unused(images: &[PathBuf]) -> Result<(), ()> {
+	let paths = images
+		.iter()
+		.filter_map(|i| fs::canonicalize(i).ok())
+		.collect::<Vec<_>>();
+	let holders = holders::find(&paths);
+
+	if holders.is_empty() {
+		return Ok(());
+	}
+
+	log::error!("disk images are in use, shut the VM down first:");
+
+	for holder in holders {
+		log::error!("  {holder}");
+	}
+
+	Err(())
+}
+
+// Snapshots only make sense across all disks of a profile, so stop at the first failure
+fn for_each_image(context: &Context, action: &str, f: impl Fn(&Path) -> Result<()>) -> Result<(), ()> {
+	if context.images.is_empty() {
+		log::error!("no qcow2 disks configured in this profile");
+		return Err(());
+	}
+
+	for image in &context.images {
+		log::info!("{action} for {}", image.display());
+
+		if let Err(e) = f(image) {
+			log::error!("{action} {e}");
+			return Err(());
+		}
+	}
 
 	Ok(())
 }
@@ -78,13 +477,35 @@ fn create_tmp_file(tmp_file: &TmpFile) -> Result<()> {
 pub fn reattach_devices(context: &Context) {
 	pat_dealloc(&context.pat_dealloc);
 	rebind_pci(&context.pci);
-	reload_drivers(context.unload_drivers.as_ref())
+	verify_pci(&context.pci);
+	reload_drivers(context.unload_drivers.as_ref());
+	restore_framebuffer(context.release_framebuffer);
+	start_display_manager(context.display_manager.as_deref());
 }
 
 pub fn detach_devices(context: &Context) -> Result<(), ()> {
+	stop_display_manager(context.display_manager.as_deref(), &context.pci)?;
+
+	if release_devices(context).is_err() {
+		start_display_manager(context.display_manager.as_deref());
+		return Err(());
+	}
+
+	Ok(())
+}
+
+fn release_devices(context: &Context) -> Result<(), ()> {
+	let released = release_framebuffer(context.release_framebuffer)?;
+
+	if check_gpu_holders(&context.pci, context.terminate_gpu_holders).is_err() {
+		undo_release_framebuffer(&released);
+		return Err(());
+	}
+
 	if unload_drivers(context.unload_drivers.as_ref()).is_err() {
 		log::info!("attempting to reload drivers");
 		reload_drivers(context.unload_drivers.as_ref());
+		undo_release_framebuffer(&released);
 		return Err(());
 	}
 
@@ -95,6 +516,7 @@ pub fn detach_devices(context: &Context) -> Result<(), ()> {
 			reload_drivers(context.unload_drivers.as_ref());
 		}
 
+		undo_release_framebuffer(&released);
 		return Err(());
 	}
 
@@ -117,6 +539,206 @@ pub fn pat_dealloc(addresses: &[String]) {
 	}
 }
 
+fn stop_display_manager(unit: Option<&str>, pci: &[PciDevice]) -> Result<(), ()> {
+	let Some(unit) = unit else {
+		return Ok(());
+	};
+
+	log::info!("stopping display manager");
+
+	if let Err(e) = systemctl::stop(unit) {
+		log::error!("stopping {unit} {e}");
+		return Err(());
+	}
+
+	if wait_for_drm_release(pci).is_err() {
+		start_display_manager(Some(unit));
+		return Err(());
+	}
+
+	Ok(())
+}
+
+// The display manager's session may take a moment to let go of the GPU after the unit stopped
+fn wait_for_drm_release(pci: &[PciDevice]) -> Result<(), ()> {
+	let nodes = pci
+		.iter()
+		.flat_map(|d| holders::drm_nodes(&d.address))
+		.collect::<Vec<_>>();
+	let start = Instant::now();
+
+	log::debug!("waiting for clients of {nodes:?} to exit");
+
+	loop {
+		let holders = holders::find(&nodes);
+
+		if holders.is_empty() {
+			return Ok(());
+		}
+
+		if start.elapsed() > DRM_RELEASE_TIMEOUT {
+			log::error!("gpu still in use after {}s:", DRM_RELEASE_TIMEOUT.as_secs());
+
+			for holder in holders {
+				log::error!("  {holder}");
+			}
+
+			return Err(());
+		}
+
+		thread::sleep(Duration::from_millis(250));
+	}
+}
+
+fn start_display_manager(unit: Option<&str>) {
+	let Some(unit) = unit else {
+		return;
+	};
+
+	log::info!("starting display manager");
+
+	if let Err(e) = systemctl::start(unit) {
+		log::error!("starting {unit} {e}");
+	}
+}
+
+fn release_framebuffer(enabled: bool) -> Result<Vec<framebuffer::Released>, ()> {
+	if !enabled {
+		return Ok(vec![]);
+	}
+
+	log::info!("releasing host framebuffer");
+
+	let mut released = vec![];
+	let result = framebuffer::release(&mut released);
+
+	if let Err(e) = framebuffer::save(&released) {
+		log::warn!("framebuffer state {e}, it won't be restored by a separate attach");
+	}
+
+	if let Err(e) = result {
+		log::error!("releasing framebuffer {e}");
+		undo_release_framebuffer(&released);
+		return Err(());
+	}
+
+	Ok(released)
+}
+
+// Failed detach, put back what this run released without relying on the saved state
+fn undo_release_framebuffer(released: &[framebuffer::Released]) {
+	restore_released(released);
+
+	if let Err(e) = framebuffer::forget(released) {
+		log::warn!("framebuffer state {e}");
+	}
+}
+
+fn restore_framebuffer(enabled: bool) {
+	if !enabled {
+		return;
+	}
+
+	match framebuffer::take_saved() {
+		Ok(released) => restore_released(&released),
+		Err(e) => log::error!("framebuffer state {e}"),
+	}
+}
+
+fn restore_released(released: &[framebuffer::Released]) {
+	if released.is_empty() {
+		return;
+	}
+
+	log::info!("restoring host framebuffer");
+
+	// rebind in reverse, framebuffer devices have to be back before their consoles
+	for released in released.iter().rev() {
+		if let Err(e) = framebuffer::restore(released) {
+			log::error!("framebuffer restore {e}");
+		}
+	}
+}
+
+// Unloading drivers or unbinding fails with little explanation while the GPU is in use
+fn check_gpu_holders(pci: &[PciDevice], terminate: bool) -> Result<(), ()> {
+	let nodes = pci
+		.iter()
+		.flat_map(|d| holders::gpu_nodes(&d.address))
+		.collect::<Vec<_>>();
+	let holders = holders::find(&nodes);
+
+	if holders.is_empty() {
+		return Ok(());
+	}
+
+	log::error!("gpu in use by the following processes:");
+
+	for holder in &holders {
+		log::error!("  {holder}");
+	}
+
+	if !terminate || !confirm("terminate these processes?") {
+		return Err(());
+	}
+
+	terminate_gpu_holders(&holders)
+}
+
+fn terminate_gpu_holders(holders: &[holders::Holder]) -> Result<(), ()> {
+	log::info!("terminating processes");
+
+	for holder in holders {
+		if let Err(e) = holders::terminate(holder) {
+			log::error!("terminating {} {e}", holder.pid);
+		}
+	}
+
+	let start = Instant::now();
+
+	while holders.iter().any(holders::is_running) {
+		if start.elapsed() > TERMINATE_TIMEOUT {
+			log::error!("processes still running after {}s", TERMINATE_TIMEOUT.as_secs());
+			return Err(());
+		}
+
+		thread::sleep(Duration::from_millis(250));
+	}
+
+	Ok(())
+}
+
+// Ctrl-C answers no, the blocking read would just resume while SIGINT is ignored
+fn confirm(question: &str) -> bool {
+	print!("{question} [y/N] ");
+	io::stdout().flush().ok();
+	INTERRUPTED.store(false, Ordering::Relaxed);
+
+	while !stdin_ready(PROMPT_POLL_INTERVAL) {
+		if INTERRUPTED.load(Ordering::Relaxed) {
+			println!();
+			return false;
+		}
+	}
+
+	let mut answer = String::new();
+	io::stdin().read_line(&mut answer).ok();
+
+	answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn stdin_ready(timeout: Duration) -> bool {
+	let mut stdin = libc::pollfd {
+		fd: libc::STDIN_FILENO,
+		events: libc::POLLIN,
+		revents: 0,
+	};
+	let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+
+	// SAFETY: the pointer refers to a single pollfd that outlives the call
+	unsafe { libc::poll(&raw mut stdin, 1, timeout) > 0 }
+}
+
 fn unload_drivers(drivers: Option<&Vec<String>>) -> Result<(), ()> {
 	if let Some(drivers) = drivers {
 		log::info!("unloading drivers");
@@ -140,16 +762,27 @@ fn reload_drivers(drivers: Option<&Vec<String>>) {
 	}
 }
 
-fn unbind_pci(addressses: &[impl AsRef<str>]) -> Result<(), Vec<&'_ str>> {
+fn unbind_pci(devices: &[PciDevice]) -> Result<(), Vec<&'_ PciDevice>> {
 	let mut unbound = vec![];
 
-	if addressses.is_empty() {
+	if devices.is_empty() {
 		return Ok(());
 	}
 
 	log::info!("unbinding pci devices");
 
-	for addr in addressses.iter().map(AsRef::as_ref) {
+	for device in devices {
+		let addr = device.address.as_str();
+
+		if let Some(method) = &device.reset_method {
+			log::debug!("setting reset method of {addr} to {method}");
+
+			if let Err(e) = pci::set_reset_method(addr, method) {
+				log::error!("pci reset method {e}");
+				return Err(unbound);
+			}
+		}
+
 		log::debug!("unbinding {addr}");
 
 		if let Err(e) = virsh::unbind_pci(addr) {
@@ -157,20 +790,31 @@ fn unbind_pci(addressses: &[impl AsRef<str>]) -> Result<(), Vec<&'_ str>> {
 			return Err(unbound);
 		}
 
-		unbound.push(addr);
+		unbound.push(device);
 	}
 
 	Ok(())
 }
 
-fn rebind_pci(addressses: &[impl AsRef<str>]) {
-	if addressses.is_empty() {
+fn rebind_pci(devices: &[impl Borrow<PciDevice>]) {
+	if devices.is_empty() {
 		return;
 	}
 
 	log::info!("rebinding pci devices");
 
-	for addr in addressses.iter().map(AsRef::as_ref) {
+	for device in devices.iter().map(Borrow::borrow) {
+		let addr = device.address.as_str();
+
+		if let Some(reset) = device.reset {
+			log::debug!("resetting {addr}");
+
+			// the device may still come back fine, rebind anyway
+			if let Err(e) = pci::reset(addr, reset) {
+				log::error!("pci reset {e}");
+			}
+		}
+
 		log::debug!("rebinding {addr}");
 		let result = virsh::rebind_pci(addr);
 
@@ -180,3 +824,13 @@ fn rebind_pci(addressses: &[impl AsRef<str>]) {
 		}
 	}
 }
+
+// Some cards (looking at you, AMD) don't survive their reset and silently stay gone
+fn verify_pci(devices: &[PciDevice]) {
+	for device in devices {
+		match pci::verify(&device.address) {
+			Ok(()) => log::debug!("{} is responding", device.address),
+			Err(e) => log::error!("pci device {e}"),
+		}
+	}
+}
@@ -0,0 +1,76 @@
+use super::{daemon, ip, passt};
+use crate::context::NetDevice;
+use anyhow::{Context, Result};
+use nix::unistd::Uid;
+use std::fs::{self, File, OpenOptions};
+use std::process::Child;
+use std::thread;
+use std::time::Duration;
+
+/// A file QEMU inherits at a fixed descriptor number.
+pub type InheritedFd = (File, i32);
+
+/// Everything that has to be kept around while QEMU runs.
+#[derive(Default)]
+pub struct Handles {
+	pub fds: Vec<InheritedFd>,
+	pub helpers: Vec<Child>,
+}
+
+/// Creates the device, storing files QEMU needs to inherit and helper processes in `handles`.
+pub fn create(device: &NetDevice, handles: &mut Handles) -> Result<()> {
+	match device {
+		NetDevice::Tap { name, bridge } => {
+			// owned by whoever runs QEMU, that's us
+			ip::create_tap(name, Uid::effective())?;
+			ip::set_master(name, bridge)?;
+			ip::set_up(name)?;
+		}
+		NetDevice::Macvtap { name, parent, mac, fd } => {
+			ip::create_macvtap(name, parent, mac)?;
+			ip::set_up(name)?;
+
+			handles.fds.push((open_macvtap(name)?, *fd));
+		}
+		NetDevice::Passt { socket, forwards } => {
+			handles.helpers.push(passt::spawn(socket, forwards)?);
+		}
+	}
+
+	Ok(())
+}
+
+/// Helper processes have to be stopped beforehand.
+pub fn remove(device: &NetDevice) -> Result<()> {
+	match device {
+		NetDevice::Tap { name, .. } | NetDevice::Macvtap { name, .. } => ip::delete(name),
+		NetDevice::Passt { socket, .. } => daemon::remove_socket(socket),
+	}
+}
+
+pub fn name(device: &NetDevice) -> &str {
+	match device {
+		NetDevice::Tap { name, .. } | NetDevice::Macvtap { name, .. } => name,
+		NetDevice::Passt { .. } => "passt",
+	}
+}
+
+// The character device is named after the interface index and created by udev, which may take a moment
+fn open_macvtap(name: &str) -> Result<File> {
+	let index = fs::read_to_string(format!("/sys/class/net/{name}/ifindex"))?;
+	let path = format!("/dev/tap{}", index.trim());
+
+	for _ in 0..20 {
+		if let Ok(file) = OpenOptions::new().read(true).write(true).open(&path) {
+			return Ok(file);
+		}
+
+		thread::sleep(Duration::from_millis(50));
+	}
+
+	OpenOptions::new()
+		.read(true)
+		.write(true)
+		.open(&path)
+		.with_context(|| format!("unable to open {path}"))
+}
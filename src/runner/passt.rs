@@ -0,0 +1,23 @@
+use super::daemon;
+use crate::context::{PortForward, Protocol};
+use anyhow::Result;
+use std::path::Path;
+use std::process::{Child, Command};
+
+/// Starts passt listening on `socket`. When started as root, passt drops its privileges on its own.
+pub fn spawn(socket: &Path, forwards: &[PortForward]) -> Result<Child> {
+	let mut cmd = Command::new("passt");
+	cmd.args(["--foreground", "--quiet", "--socket"]).arg(socket);
+
+	for forward in forwards {
+		let flag = match forward.protocol {
+			Protocol::Tcp => "--tcp-ports",
+			Protocol::Udp => "--udp-ports",
+		};
+
+		cmd.arg(flag)
+			.arg(format!("{}:{}", forward.host_port, forward.guest_port));
+	}
+
+	daemon::spawn(&mut cmd, socket)
+}
@@ -0,0 +1,77 @@
+use crate::context::PciReset;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+pub fn set_reset_method(address: &str, method: &str) -> Result<()> {
+	write(address, "reset_method", method)
+}
+
+pub fn reset(address: &str, reset: PciReset) -> Result<()> {
+	match reset {
+		PciReset::Function => write(address, "reset", "1"),
+		PciReset::Bus => {
+			// temporarily restrict the device to bus resets, then restore whatever was configured
+			let previous = read(address, "reset_method")?;
+			write(address, "reset_method", "bus")?;
+			let result = write(address, "reset", "1");
+
+			// the reset's outcome matters more, don't let a failed restore hide it
+			if let Err(e) = write(address, "reset_method", previous.trim()) {
+				log::error!("restoring reset_method of {address} {e}");
+			}
+
+			result
+		}
+	}
+}
+
+/// Reads vendor and device ID from config space, which fails or reads all ones when the device is gone,
+/// and compares them to the IDs the kernel recorded on enumeration.
+pub fn verify(address: &str) -> Result<()> {
+	let config = fs::read(device_path(address).join("config"))
+		.with_context(|| format!("{address} did not come back: unable to read config space"))?;
+
+	let Some(&[vendor_low, vendor_high, device_low, device_high]) = config.get(0..4) else {
+		bail!("{address} did not come back: config space truncated");
+	};
+
+	let vendor = u16::from_le_bytes([vendor_low, vendor_high]);
+	let device = u16::from_le_bytes([device_low, device_high]);
+
+	if vendor == 0xffff {
+		bail!("{address} did not come back: not responding");
+	}
+
+	let expected_vendor = read_id(address, "vendor")?;
+	let expected_device = read_id(address, "device")?;
+
+	if (vendor, device) != (expected_vendor, expected_device) {
+		bail!(
+			"{address} did not come back: reports {vendor:04x}:{device:04x}, expected {expected_vendor:04x}:{expected_device:04x}"
+		);
+	}
+
+	Ok(())
+}
+
+fn read_id(address: &str, attribute: &str) -> Result<u16> {
+	let value = read(address, attribute)?;
+	let value = value.trim().trim_start_matches("0x");
+
+	u16::from_str_radix(value, 16).with_context(|| format!("invalid {attribute} id of {address}: {value}"))
+}
+
+fn read(address: &str, attribute: &str) -> Result<String> {
+	let path = device_path(address).join(attribute);
+	fs::read_to_string(&path).with_context(|| format!("unable to read {}", path.display()))
+}
+
+fn write(address: &str, attribute: &str, value: &str) -> Result<()> {
+	let path = device_path(address).join(attribute);
+	fs::write(&path, value).with_context(|| format!("unable to write '{value}' to {}", path.display()))
+}
+
+fn device_path(address: &str) -> PathBuf {
+	PathBuf::from(format!("/sys/bus/pci/devices/{address}"))
+}
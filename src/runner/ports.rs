@@ -0,0 +1,16 @@
+use crate::context::{PortForward, Protocol};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+
+/// Checks whether the host port of `forward` is already bound, QEMU binds it on all addresses.
+pub fn is_in_use(forward: &PortForward) -> bool {
+	let address = (Ipv4Addr::UNSPECIFIED, forward.host_port);
+
+	let result = match forward.protocol {
+		Protocol::Tcp => TcpListener::bind(address).map(drop),
+		Protocol::Udp => UdpSocket::bind(address).map(drop),
+	};
+
+	// anything else, e.g. lacking permissions for low ports, will be reported by QEMU
+	matches!(result, Err(e) if e.kind() == ErrorKind::AddrInUse)
+}
@@ -1,15 +1,34 @@
+use super::netdev::InheritedFd;
+use super::taskset;
 use crate::context::Context;
+use nix::libc;
 use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
 use std::process::{Command, ExitStatus};
+use std::thread;
 
 const QEMU_CMD: &str = "qemu-system-x86_64";
 
-pub fn run_qemu(context: &Context) -> Result<ExitStatus, io::Error> {
-	get_command(context)
-		.args(&context.args)
-		.envs(&context.env)
-		.spawn()
-		.and_then(|mut handle| handle.wait())
+pub fn run_qemu(context: &Context, fds: &[InheritedFd]) -> Result<ExitStatus, io::Error> {
+	let mut cmd = get_command(context);
+	cmd.args(&context.args).envs(&context.env);
+	inherit_fds(&mut cmd, fds);
+
+	let mut handle = cmd.spawn()?;
+
+	// pinning failures only cost performance, the VM is still usable
+	if !context.vcpu_pins.is_empty() {
+		let (pid, pins) = (handle.id(), context.vcpu_pins.clone());
+
+		thread::spawn(move || {
+			if let Err(e) = taskset::pin_vcpus(pid, &pins) {
+				log::error!("pinning vcpus {e}");
+			}
+		});
+	}
+
+	handle.wait()
 }
 
 fn get_command(context: &Context) -> Command {
@@ -24,3 +43,25 @@ fn get_command(context: &Context) -> Command {
 		}
 	}
 }
+
+// Files are opened with O_CLOEXEC, duplicating them clears it and places them where QEMU expects them
+fn inherit_fds(cmd: &mut Command, fds: &[InheritedFd]) {
+	if fds.is_empty() {
+		return;
+	}
+
+	let fds = fds.iter().map(|(file, fd)| (file.as_raw_fd(), *fd)).collect::<Vec<_>>();
+
+	// SAFETY: dup2 is async-signal-safe and the closure doesn't allocate
+	unsafe {
+		cmd.pre_exec(move || {
+			for &(source, target) in &fds {
+				if libc::dup2(source, target) == -1 {
+					return Err(io::Error::last_os_error());
+				}
+			}
+
+			Ok(())
+		});
+	}
+}
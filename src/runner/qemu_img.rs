@@ -0,0 +1,61 @@
+use super::util::{get_output, run_command};
+use crate::context::DiskFormat;
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::{self, Path, PathBuf};
+use std::process::Command;
+
+#[derive(Deserialize)]
+struct ImageInfo {
+	filename: PathBuf,
+}
+
+pub fn create_overlay(path: &Path, backing_file: &Path, backing_format: DiskFormat) -> Result<()> {
+	// relative backing files are resolved relative to the overlay, not the working directory
+	let backing_file = path::absolute(backing_file)?;
+
+	run_command(
+		Command::new("qemu-img")
+			.args(["create", "-f", "qcow2", "-F", backing_format.as_str(), "-b"])
+			.arg(backing_file)
+			.arg(path),
+	)
+}
+
+pub fn create_snapshot(image: &Path, name: &str) -> Result<()> {
+	run_snapshot_cmd("-c", name, image)
+}
+
+pub fn revert_snapshot(image: &Path, name: &str) -> Result<()> {
+	run_snapshot_cmd("-a", name, image)
+}
+
+pub fn delete_snapshot(image: &Path, name: &str) -> Result<()> {
+	run_snapshot_cmd("-d", name, image)
+}
+
+pub fn list_snapshots(image: &Path) -> Result<String> {
+	// only reads, so it's safe to share the image with a running VM
+	get_output(Command::new("qemu-img").args(["snapshot", "-l", "-U"]).arg(image))
+}
+
+/// The image followed by its backing files, as QEMU opens them.
+pub fn backing_chain(image: &Path) -> Result<Vec<PathBuf>> {
+	let output = get_output(
+		Command::new("qemu-img")
+			.args(["info", "--backing-chain", "--output=json", "-U"])
+			.arg(image),
+	)?;
+
+	let chain = serde_json::from_str::<Vec<ImageInfo>>(&output)?;
+	Ok(chain.into_iter().map(|i| i.filename).collect())
+}
+
+/// Writes the contents of `image` into its backing file, leaving `image` as is.
+pub fn commit(image: &Path) -> Result<()> {
+	run_command(Command::new("qemu-img").args(["commit", "-d"]).arg(image))
+}
+
+fn run_snapshot_cmd(flag: &str, name: &str, image: &Path) -> Result<()> {
+	run_command(Command::new("qemu-img").args(["snapshot", flag, name]).arg(image))
+}
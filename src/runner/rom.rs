@@ -0,0 +1,58 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+const ROM_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const PCIR_SIGNATURE: &[u8] = b"PCIR";
+
+/// Reads the option ROM of a PCI device from sysfs.  
+/// The ROM is only readable while enabled, it is disabled again regardless of the outcome.
+pub fn read(address: &str) -> Result<Vec<u8>> {
+	let path = PathBuf::from(format!("/sys/bus/pci/devices/{address}/rom"));
+
+	fs::write(&path, "1").with_context(|| format!("unable to enable {}", path.display()))?;
+	let rom = fs::read(&path).with_context(|| format!("unable to read {}", path.display()));
+
+	if let Err(e) = fs::write(&path, "0") {
+		log::warn!("unable to disable {}: {e}", path.display());
+	}
+
+	rom
+}
+
+pub fn validate(rom: &[u8]) -> Result<()> {
+	if !is_rom_image(rom) {
+		bail!("missing option ROM signature, the device may be in use or the ROM shadowed");
+	}
+
+	Ok(())
+}
+
+/// Dumps of NVIDIA cards may carry a proprietary header in front of the actual ROM image,
+/// which the guest refuses to load. Returns the ROM starting at the first valid image.
+pub fn strip_nvidia_header(rom: &[u8]) -> Result<&[u8]> {
+	let offset = (0..rom.len())
+		.find(|&offset| is_rom_image(&rom[offset..]))
+		.context("no option ROM image found")?;
+
+	match offset {
+		0 => log::info!("no header found, ROM left as-is"),
+		_ => log::info!("stripped {offset} byte header"),
+	}
+
+	Ok(&rom[offset..])
+}
+
+fn is_rom_image(image: &[u8]) -> bool {
+	if !image.starts_with(&ROM_SIGNATURE) {
+		return false;
+	}
+
+	// offset 0x18 points to the PCI data structure, which has its own signature
+	let Some(&[low, high]) = image.get(0x18..0x1a) else {
+		return false;
+	};
+
+	let pcir = u16::from_le_bytes([low, high]) as usize;
+	image.get(pcir..pcir + PCIR_SIGNATURE.len()) == Some(PCIR_SIGNATURE)
+}
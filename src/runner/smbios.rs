@@ -0,0 +1,26 @@
+use crate::context::dmi;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Writes the host's SMBIOS structures to `output`, leaving out the types in `exclude`.
+pub fn dump(output: &Path, exclude: &[u8], uuid: Option<&str>) -> Result<usize> {
+	let mut structures = dmi::read_host().context("unable to read host tables")?;
+	structures.retain(|s| !exclude.contains(&s.kind));
+
+	if let Some(uuid) = uuid {
+		let Some(system) = structures.iter_mut().find(|s| s.kind == dmi::SYSTEM_INFORMATION) else {
+			bail!("no system information structure to set the uuid in");
+		};
+
+		if !dmi::set_uuid(system, uuid) {
+			bail!("unable to set uuid {uuid}");
+		}
+	}
+
+	fs::write(
+		output,
+		structures.iter().flat_map(|s| &s.bytes).copied().collect::<Vec<_>>(),
+	)?;
+	Ok(structures.len())
+}
@@ -0,0 +1,141 @@
+use super::qemu_img;
+use crate::context::{external_snapshot, DiskFormat};
+use anyhow::{bail, Context, Result};
+use nix::unistd::{self, Gid, Uid};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{self, Path, PathBuf};
+
+/// Moves the current state of `image` into the snapshot and continues in a new layer on top of it.
+/// The new layer takes the image's path, so the config keeps referring to the current state.
+pub fn create_external(image: &Path, name: &str) -> Result<()> {
+	let snapshot = snapshot_path(image, name)?;
+
+	if snapshot.exists() {
+		bail!("{} already exists", snapshot.display());
+	}
+
+	fs::rename(image, &snapshot).with_context(|| format!("unable to move {} aside", image.display()))?;
+
+	if let Err(e) = create_layer(image, &snapshot) {
+		// nothing refers to the snapshot yet, the image can go back as it was
+		if let Err(e) = fs::rename(&snapshot, image) {
+			log::error!("moving {} back {e}", image.display());
+		}
+
+		return Err(e);
+	}
+
+	Ok(())
+}
+
+/// Discards everything written since the snapshot, including the external snapshots taken after it.
+pub fn revert_external(image: &Path, name: &str) -> Result<()> {
+	let snapshot = snapshot_path(image, name)?;
+	let chain = chain(image)?;
+	let position = position(&chain, &snapshot, image)?;
+	let discarded = &chain[..position];
+
+	ensure_no_internal_snapshots(discarded)?;
+
+	// build the new layer first, the image is only replaced once that worked
+	let layer = temporary_path(image);
+	create_layer(&layer, &snapshot)?;
+	fs::rename(&layer, image).with_context(|| format!("unable to replace {}", image.display()))?;
+
+	// later snapshots only lead to the discarded state
+	for newer in &discarded[1..] {
+		fs::remove_file(newer).with_context(|| format!("unable to delete {}", newer.display()))?;
+	}
+
+	Ok(())
+}
+
+/// Removes the snapshot from the backing chain, the layer on top of it takes in its contents and its place.
+pub fn delete_external(image: &Path, name: &str) -> Result<()> {
+	let snapshot = snapshot_path(image, name)?;
+	let chain = chain(image)?;
+	let position = position(&chain, &snapshot, image)?;
+	let above = &chain[position - 1];
+
+	// the snapshot's file replaces the layer above, which loses what only it holds
+	ensure_no_internal_snapshots(std::slice::from_ref(above))?;
+
+	qemu_img::commit(above)?;
+	fs::rename(&snapshot, above).with_context(|| format!("unable to replace {}", above.display()))?;
+
+	Ok(())
+}
+
+/// Names of the external snapshots `image` is based on, newest first.
+pub fn list_external(image: &Path) -> Result<Vec<String>> {
+	let image = path::absolute(image)?;
+	let prefix = format!("{}.", image.file_name().unwrap_or_default().to_string_lossy());
+
+	let names = chain(&image)?
+		.into_iter()
+		.skip(1)
+		.filter(|p| p.parent() == image.parent())
+		.filter_map(|p| Some(p.file_name()?.to_string_lossy().strip_prefix(&prefix)?.to_owned()))
+		.collect();
+
+	Ok(names)
+}
+
+fn snapshot_path(image: &Path, name: &str) -> Result<PathBuf> {
+	if name.is_empty() || name.contains('/') {
+		bail!("invalid snapshot name '{name}'");
+	}
+
+	Ok(path::absolute(external_snapshot(image, name))?)
+}
+
+// Backing files are recorded as absolute paths, the image itself may be given relative
+fn chain(image: &Path) -> Result<Vec<PathBuf>> {
+	qemu_img::backing_chain(image)?
+		.iter()
+		.map(|p| Ok(path::absolute(p)?))
+		.collect()
+}
+
+fn position(chain: &[PathBuf], snapshot: &Path, image: &Path) -> Result<usize> {
+	match chain.iter().skip(1).position(|p| p == snapshot) {
+		Some(position) => Ok(position + 1),
+		None => bail!(
+			"{} is not a snapshot {} is based on",
+			snapshot.display(),
+			image.display()
+		),
+	}
+}
+
+// Internal snapshots live inside a layer's file and go with it
+fn ensure_no_internal_snapshots(layers: &[PathBuf]) -> Result<()> {
+	for layer in layers {
+		if !qemu_img::list_snapshots(layer)?.trim().is_empty() {
+			bail!("{} has internal snapshots, which would be lost", layer.display());
+		}
+	}
+
+	Ok(())
+}
+
+// The layer is created by root, but should stay accessible to whoever owns the image
+fn create_layer(path: &Path, backing_file: &Path) -> Result<()> {
+	let metadata = fs::metadata(backing_file)?;
+
+	qemu_img::create_overlay(path, backing_file, DiskFormat::Qcow2)?;
+	fs::set_permissions(path, metadata.permissions())?;
+	unistd::chown(
+		path,
+		Some(Uid::from_raw(metadata.uid())),
+		Some(Gid::from_raw(metadata.gid())),
+	)?;
+
+	Ok(())
+}
+
+fn temporary_path(image: &Path) -> PathBuf {
+	let file = image.file_name().unwrap_or_default().to_string_lossy();
+	image.with_file_name(format!(".{file}.tmp"))
+}
@@ -0,0 +1,48 @@
+use super::daemon;
+use anyhow::Result;
+use nix::unistd::{self, Gid, Uid, User};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Child, Command};
+
+// Distributions create this user for TPM software, swtpm included
+const TPM_USER: &str = "tss";
+
+/// Starts swtpm with its control socket at `socket`, keeping the TPM state in `state`.  
+/// As root, swtpm runs as the tss user if it exists. Both directories are handed over to it.
+pub fn spawn(socket: &Path, state: &Path) -> Result<Child> {
+	let user = match Uid::effective().is_root() {
+		true => User::from_name(TPM_USER)?,
+		false => None,
+	};
+
+	fs::create_dir_all(state)?;
+	fs::set_permissions(state, fs::Permissions::from_mode(0o700))?;
+
+	let mut cmd = Command::new("swtpm");
+	cmd.args(["socket", "--tpm2", "--terminate"]);
+	cmd.arg("--tpmstate").arg(format!("dir={},mode=0600", state.display()));
+	cmd.arg("--ctrl").arg(format!("type=unixio,path={}", socket.display()));
+
+	if let (Some(user), Some(socket_dir)) = (user, socket.parent()) {
+		fs::create_dir_all(socket_dir)?;
+		chown_all(state, user.uid, user.gid)?;
+		unistd::chown(socket_dir, Some(user.uid), Some(user.gid))?;
+
+		cmd.arg("--runas").arg(user.uid.to_string());
+	}
+
+	daemon::spawn(&mut cmd, socket)
+}
+
+// state may be left over from running as another user
+fn chown_all(dir: &Path, uid: Uid, gid: Gid) -> Result<()> {
+	unistd::chown(dir, Some(uid), Some(gid))?;
+
+	for entry in fs::read_dir(dir)? {
+		unistd::chown(&entry?.path(), Some(uid), Some(gid))?;
+	}
+
+	Ok(())
+}
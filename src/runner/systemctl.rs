@@ -0,0 +1,11 @@
+use super::util::run_command;
+use anyhow::Result;
+use std::process::Command;
+
+pub fn stop(unit: &str) -> Result<()> {
+	run_command(Command::new("systemctl").arg("stop").arg(unit))
+}
+
+pub fn start(unit: &str) -> Result<()> {
+	run_command(Command::new("systemctl").arg("start").arg(unit))
+}
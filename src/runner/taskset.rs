@@ -0,0 +1,81 @@
+use super::holders;
+use super::util::run_command;
+use anyhow::{bail, Result};
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pins each vCPU thread of the running QEMU to the host CPU at the same index in `pins`.
+/// The threads are found by name, which requires QEMU to run with `-name ...,debug-threads=on`.
+pub fn pin_vcpus(qemu: u32, pins: &[usize]) -> Result<()> {
+	let threads = wait_for_vcpus(qemu, pins.len())?;
+
+	for (vcpu, thread) in threads.into_iter().enumerate() {
+		let cpu = pins[vcpu].to_string();
+		run_command(
+			Command::new("taskset")
+				.arg("--pid")
+				.arg("--cpu-list")
+				.arg(cpu)
+				.arg(thread),
+		)?;
+	}
+
+	log::info!("pinned {} vcpus", pins.len());
+	Ok(())
+}
+
+// QEMU creates the vCPU threads, named "CPU <n>/KVM", while setting up the machine
+fn wait_for_vcpus(qemu: u32, count: usize) -> Result<Vec<String>> {
+	let start = Instant::now();
+
+	loop {
+		let threads = find_vcpus(qemu)?;
+
+		if threads.iter().all(Option::is_some) && threads.len() == count {
+			return Ok(threads.into_iter().flatten().collect());
+		}
+
+		// the process is reaped by whoever waits on it, it lingers as a zombie until then
+		if !holders::is_alive(qemu) {
+			bail!("qemu exited before its vcpus could be pinned");
+		}
+
+		if start.elapsed() > STARTUP_TIMEOUT {
+			bail!("qemu didn't start {count} vcpus in time");
+		}
+
+		thread::sleep(Duration::from_millis(50));
+	}
+}
+
+// indexed by vCPU, holes are threads that don't exist yet
+fn find_vcpus(pid: u32) -> Result<Vec<Option<String>>> {
+	let mut vcpus = vec![];
+
+	for entry in fs::read_dir(format!("/proc/{pid}/task"))? {
+		let path = entry?.path();
+		let Ok(name) = fs::read_to_string(path.join("comm")) else {
+			continue;
+		};
+
+		let Some(index) = name.trim().strip_prefix("CPU ").and_then(|n| n.strip_suffix("/KVM")) else {
+			continue;
+		};
+
+		let Ok(index) = index.parse::<usize>() else {
+			continue;
+		};
+
+		if vcpus.len() <= index {
+			vcpus.resize(index + 1, None);
+		}
+
+		vcpus[index] = path.file_name().map(|t| t.to_string_lossy().into_owned());
+	}
+
+	Ok(vcpus)
+}
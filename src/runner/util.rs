@@ -2,10 +2,15 @@ use anyhow::{bail, Result};
 use std::process::Command;
 
 pub fn run_command(cmd: &mut Command) -> Result<()> {
+	get_output(cmd)?;
+	Ok(())
+}
+
+pub fn get_output(cmd: &mut Command) -> Result<String> {
 	let output = cmd.output()?;
 
 	if output.status.success() {
-		return Ok(());
+		return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
 	}
 
 	let stderr = std::str::from_utf8(&output.stderr)
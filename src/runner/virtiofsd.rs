@@ -0,0 +1,28 @@
+use super::daemon;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Child, Command};
+
+// Usually not in PATH, distributions put it in various places
+const LOCATIONS: [&str; 4] = [
+	"/usr/libexec/virtiofsd",
+	"/usr/lib/virtiofsd",
+	"/usr/lib/qemu/virtiofsd",
+	"/usr/bin/virtiofsd",
+];
+
+/// Starts virtiofsd sharing `path` on `socket`.
+pub fn spawn(socket: &Path, path: &Path) -> Result<Child> {
+	let binary = LOCATIONS
+		.into_iter()
+		.map(Path::new)
+		.find(|p| p.exists())
+		.context("virtiofsd not found")?;
+
+	let mut cmd = Command::new(binary);
+	cmd.arg("--socket-path").arg(socket);
+	cmd.arg("--shared-dir").arg(path);
+	cmd.args(["--cache", "auto"]);
+
+	daemon::spawn(&mut cmd, socket)
+}